@@ -7,6 +7,9 @@ pushd ..
 popd
 echo "copying binaries to target directories"
 sudo cp ../../target/debug/homesec_bootstrap /usr/bin/homesec-bootstrap
+if [ ! -f /etc/homesec-key ]; then
+    echo "warning: /etc/homesec-key is missing; copy the cluster key from another node before starting the daemon"
+fi
 sudo cp ./homesec-bootstrap.service /etc/systemd/system/homesec-bootstrap.service
 sudo systemctl start homesec-bootstrap
 echo "running 'systemctl daemon-reload'"
@@ -0,0 +1,291 @@
+//! Local cache of the files needed to install k3s without internet
+//! access, and the plain HTTP server the master shares it with.
+//!
+//! A cache is a directory holding a `SUMS_FILE` in `sha256sum` format
+//! that lists every artifact, at least `k3s`, `install.sh` and one
+//! `k3s-airgap-images*` tarball. Agents download from the master over
+//! HTTP, so the integrity of what they fetch rests on the digest of the
+//! sums file, which the master announces in its authenticated
+//! `ConnectionDetails`.
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const SUMS_FILE: &str = "SHA256SUMS";
+
+/// Where agents keep artifacts downloaded from the master.
+pub const FETCHED_ARTIFACTS_PATH: &str = "/var/lib/homesec/artifacts";
+
+pub const DEFAULT_ARTIFACT_PORT: u16 = 43080;
+
+const REQUIRED: &[&str] = &["k3s", "install.sh"];
+
+const IMAGES_PREFIX: &str = "k3s-airgap-images";
+
+const MAX_REQUEST_LEN: usize = 8192;
+
+/// Connections the artifact server handles at once. Further ones are
+/// turned away with a 503 until one finishes.
+pub const MAX_ARTIFACT_CONNECTIONS: usize = 16;
+
+/// Where the master serves its cache, as announced to agents.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct ArtifactSource {
+    pub port: u16,
+    /// SHA-256 of the master's `SUMS_FILE`.
+    pub sums: [u8; 32],
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn sha256_file(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hex(&hasher.finalize()))
+}
+
+/// Parses `sha256sum` output into (name, hex digest) pairs. Names must be
+/// plain file names, so a sums file can never point outside its cache.
+pub fn parse_sums(text: &str) -> Result<Vec<(String, String)>> {
+    let mut entries = Vec::new();
+    for line in text.lines().filter(|line| !line.trim().is_empty()) {
+        let (digest, name) = line.split_once(char::is_whitespace)
+            .ok_or_else(|| anyhow!("malformed {} line: {}", SUMS_FILE, line))?;
+        let name = name.trim_start();
+        // sha256sum marks files hashed in binary mode with a '*'.
+        let name = name.strip_prefix('*').unwrap_or(name);
+        if digest.len() != 64 || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(anyhow!("malformed digest for {}", name));
+        }
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." || name == SUMS_FILE {
+            return Err(anyhow!("invalid artifact name {:?}", name));
+        }
+        entries.push((name.to_string(), digest.to_ascii_lowercase()));
+    }
+    for required in REQUIRED {
+        if !entries.iter().any(|(name, _)| name == required) {
+            return Err(anyhow!("{} does not list {}", SUMS_FILE, required));
+        }
+    }
+    if !entries.iter().any(|(name, _)| name.starts_with(IMAGES_PREFIX)) {
+        return Err(anyhow!("{} does not list a {} tarball", SUMS_FILE, IMAGES_PREFIX));
+    }
+    Ok(entries)
+}
+
+/// A directory of artifacts whose checksums have been verified.
+#[derive(Clone, Debug)]
+pub struct ArtifactCache {
+    pub dir: PathBuf,
+    /// (name, hex digest) of every artifact.
+    pub files: Vec<(String, String)>,
+    sums: [u8; 32],
+}
+
+impl ArtifactCache {
+    /// Opens the cache at `dir`, failing if any listed file is missing or
+    /// does not match its checksum.
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        let sums = std::fs::read(dir.join(SUMS_FILE))
+            .map_err(|e| anyhow!("failed to read {}: {}", dir.join(SUMS_FILE).display(), e))?;
+        let files = parse_sums(std::str::from_utf8(&sums)?)?;
+        for (name, digest) in &files {
+            let actual = sha256_file(&dir.join(name))
+                .map_err(|e| anyhow!("failed to read artifact {}: {}", name, e))?;
+            if &actual != digest {
+                return Err(anyhow!("checksum mismatch for artifact {}", name));
+            }
+        }
+        Ok(Self {
+            dir,
+            files,
+            sums: Sha256::digest(&sums).into(),
+        })
+    }
+
+    pub fn source(&self, port: u16) -> ArtifactSource {
+        ArtifactSource {
+            port,
+            sums: self.sums,
+        }
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    pub fn images(&self) -> Vec<PathBuf> {
+        self.files.iter()
+            .filter(|(name, _)| name.starts_with(IMAGES_PREFIX))
+            .map(|(name, _)| self.path(name))
+            .collect()
+    }
+
+    /// Downloads the cache served at `addr` into `dir`, keeping files that
+    /// are already there with the right checksum.
+    pub fn fetch<P: AsRef<Path>>(addr: SocketAddr, expected: [u8; 32], dir: P) -> Result<Self> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+        let mut sums = Vec::new();
+        http_get(addr, SUMS_FILE, &mut sums)?;
+        if Sha256::digest(&sums)[..] != expected[..] {
+            return Err(anyhow!("{} from {} does not match the announced digest", SUMS_FILE, addr));
+        }
+        let files = parse_sums(std::str::from_utf8(&sums)?)?;
+        for (name, digest) in &files {
+            let path = dir.join(name);
+            if path.exists() && &sha256_file(&path)? == digest {
+                continue;
+            }
+            println!("downloading artifact {} from {}", name, addr);
+            let partial = dir.join(format!("{}.part", name));
+            let mut hasher = Sha256::new();
+            {
+                let mut out = HashingWriter { file: File::create(&partial)?, hasher: &mut hasher };
+                http_get(addr, name, &mut out)?;
+            }
+            if &hex(&hasher.finalize()) != digest {
+                std::fs::remove_file(&partial)?;
+                return Err(anyhow!("checksum mismatch for artifact {} from {}", name, addr));
+            }
+            std::fs::rename(&partial, &path)?;
+        }
+        std::fs::write(dir.join(SUMS_FILE), &sums)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            files,
+            sums: expected,
+        })
+    }
+}
+
+struct HashingWriter<'a> {
+    file: File,
+    hasher: &'a mut Sha256,
+}
+
+impl Write for HashingWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.file.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Minimal HTTP/1.0 GET of `/<name>` from `addr`, streaming the body.
+fn http_get<W: Write>(addr: SocketAddr, name: &str, out: &mut W) -> Result<()> {
+    let mut stream = TcpStream::connect_timeout(&addr, Duration::from_secs(10))?;
+    stream.set_read_timeout(Some(Duration::from_secs(60)))?;
+    write!(stream, "GET /{} HTTP/1.0\r\nHost: {}\r\n\r\n", name, addr)?;
+    let mut reader = BufReader::new(stream);
+    let mut status = String::new();
+    reader.read_line(&mut status)?;
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(anyhow!("GET /{} from {} failed: {}", name, addr, status.trim()));
+    }
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+    io::copy(&mut reader, out)?;
+    Ok(())
+}
+
+/// Serves `SUMS_FILE` and every artifact it lists on `listener`, one
+/// thread per connection up to `MAX_ARTIFACT_CONNECTIONS`, until the
+/// process exits.
+pub fn serve_artifacts(cache: ArtifactCache, listener: TcpListener) {
+    std::thread::spawn(move || {
+        let active = Arc::new(AtomicUsize::new(0));
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    println!("artifact server accept failed: {}", e);
+                    continue;
+                }
+            };
+            if active.fetch_add(1, Ordering::SeqCst) >= MAX_ARTIFACT_CONNECTIONS {
+                active.fetch_sub(1, Ordering::SeqCst);
+                turn_away(stream);
+                continue;
+            }
+            let slot = Slot(active.clone());
+            let cache = cache.clone();
+            std::thread::spawn(move || {
+                let peer = stream.peer_addr().ok();
+                if let Err(e) = handle(&cache, stream) {
+                    println!("artifact request from {:?} failed: {}", peer, e);
+                }
+                drop(slot);
+            });
+        }
+    });
+}
+
+/// Answers a connection over the cap with a 503. The request is read
+/// first, for at most a moment, since closing with it unread would reset
+/// the connection before the agent sees the answer.
+fn turn_away(mut stream: TcpStream) {
+    let _ = stream.set_read_timeout(Some(Duration::from_millis(100)));
+    let _ = stream.read(&mut [0u8; MAX_REQUEST_LEN]);
+    let _ = stream.write_all(b"HTTP/1.0 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n");
+}
+
+/// A connection counted against `MAX_ARTIFACT_CONNECTIONS`, released
+/// when dropped.
+struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn handle(cache: &ArtifactCache, mut stream: TcpStream) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    // An agent that stops reading must not hold its slot forever.
+    stream.set_write_timeout(Some(Duration::from_secs(60)))?;
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf)?;
+        if n == 0 || request.len() + n > MAX_REQUEST_LEN {
+            return Err(anyhow!("incomplete request"));
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut parts = request.split_whitespace();
+    let name = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path)) => path.trim_start_matches('/'),
+        _ => "",
+    };
+    let known = name == SUMS_FILE || cache.files.iter().any(|(file, _)| file == name);
+    if !known {
+        stream.write_all(b"HTTP/1.0 404 Not Found\r\nContent-Length: 0\r\n\r\n")?;
+        return Ok(());
+    }
+    let mut file = File::open(cache.path(name))?;
+    let len = file.metadata()?.len();
+    write!(stream, "HTTP/1.0 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\n\r\n", len)?;
+    io::copy(&mut file, &mut stream)?;
+    Ok(())
+}
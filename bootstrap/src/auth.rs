@@ -0,0 +1,188 @@
+use crate::election::{Clock, SystemClock};
+use anyhow::{anyhow, Result};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Pre-shared cluster key. Every node must hold the same contents, e.g.
+/// `head -c 32 /dev/urandom | base64 > /etc/homesec-key`.
+pub const KEY_PATH: &str = "/etc/homesec-key";
+
+/// How far a message timestamp may drift from the local clock before the
+/// message is treated as a replay.
+pub const DEFAULT_MAX_SKEW: Duration = Duration::from_secs(60);
+
+const MIN_KEY_LEN: usize = 16;
+
+const TOKEN_KEY_INFO: &[u8] = b"homesec join token";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Authenticated body of every datagram, following the `wire` header.
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    timestamp: u64,
+    nonce: u64,
+    payload: Vec<u8>,
+    mac: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    /// The datagram was not an envelope or its MAC did not verify.
+    Unauthenticated,
+    /// The envelope verified but was stale or its nonce was already seen.
+    Replayed,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthError::Unauthenticated => write!(f, "unauthenticated"),
+            AuthError::Replayed => write!(f, "replayed"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// Number of datagrams dropped by an `Authenticator`, by reason.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AuthStats {
+    pub unauthenticated: u64,
+    pub replayed: u64,
+}
+
+/// Seals outgoing payloads and verifies incoming ones with HMAC-SHA256
+/// over the wire header, timestamp, nonce and payload.
+#[derive(Clone)]
+pub struct Authenticator {
+    key: Vec<u8>,
+    pub max_skew: Duration,
+    pub stats: AuthStats,
+    seen: HashMap<u64, SystemTime>,
+    clock: Arc<dyn Clock>,
+}
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as _
+}
+
+impl Authenticator {
+    pub fn new(key: Vec<u8>) -> Result<Self> {
+        Self::with_clock(key, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(key: Vec<u8>, clock: Arc<dyn Clock>) -> Result<Self> {
+        if key.len() < MIN_KEY_LEN {
+            return Err(anyhow!("cluster key must be at least {} bytes", MIN_KEY_LEN));
+        }
+        Ok(Self {
+            key,
+            max_skew: DEFAULT_MAX_SKEW,
+            stats: AuthStats::default(),
+            seen: HashMap::new(),
+            clock,
+        })
+    }
+
+    /// Reads the cluster key from `path`, ignoring surrounding whitespace.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Err(anyhow!("cluster key not found at {}; the same key must be provisioned on every node", path.display()));
+        }
+        let key = std::fs::read_to_string(path)?;
+        Self::new(key.trim().as_bytes().to_vec())
+    }
+
+    fn mac(&self, header: &[u8], timestamp: u64, nonce: u64, payload: &[u8]) -> HmacSha256 {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(&self.key).expect("hmac accepts any key length");
+        mac.update(header);
+        mac.update(&timestamp.to_be_bytes());
+        mac.update(&nonce.to_be_bytes());
+        mac.update(payload);
+        mac
+    }
+
+    /// Wraps `payload` in an envelope whose MAC also covers `header`.
+    pub fn seal(&self, header: &[u8], payload: Vec<u8>) -> Result<Vec<u8>> {
+        let timestamp = millis(self.clock.now());
+        let nonce = rand::random::<u64>();
+        let mac = self.mac(header, timestamp, nonce, &payload).finalize().into_bytes().to_vec();
+        Ok(bincode::serialize(&Envelope { timestamp, nonce, payload, mac })?)
+    }
+
+    /// Derives the key used to encrypt the k3s token for `agent`. Only
+    /// holders of the cluster key can derive it, and each agent's key is
+    /// distinct so a response captured for one node is useless to another.
+    fn token_cipher(&self, agent: Uuid) -> ChaCha20Poly1305 {
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(None, &self.key)
+            .expand_multi_info(&[TOKEN_KEY_INFO, agent.as_bytes()], &mut key)
+            .expect("32 bytes is a valid hkdf output length");
+        ChaCha20Poly1305::new(&key.into())
+    }
+
+    /// Encrypts `token` for `agent`, returning the nonce and ciphertext.
+    /// The master's hid is bound in as associated data.
+    pub fn seal_token(&self, agent: Uuid, master: Uuid, token: &str) -> Result<([u8; 12], Vec<u8>)> {
+        let nonce = rand::random::<[u8; 12]>();
+        let ciphertext = self.token_cipher(agent)
+            .encrypt(&nonce.into(), Payload { msg: token.as_bytes(), aad: master.as_bytes() })
+            .map_err(|_| anyhow!("failed to encrypt join token"))?;
+        Ok((nonce, ciphertext))
+    }
+
+    pub fn open_token(&self, agent: Uuid, master: Uuid, nonce: &[u8; 12], ciphertext: &[u8]) -> Result<String> {
+        let token = self.token_cipher(agent)
+            .decrypt(nonce.into(), Payload { msg: ciphertext, aad: master.as_bytes() })
+            .map_err(|_| anyhow!("failed to decrypt join token from {}", master))?;
+        Ok(String::from_utf8(token)?)
+    }
+
+    /// Verifies the envelope in `data` against `header` and returns its
+    /// payload.
+    pub fn open(&mut self, header: &[u8], data: &[u8]) -> std::result::Result<Vec<u8>, AuthError> {
+        let result = self.verify(header, data);
+        match result {
+            Err(AuthError::Unauthenticated) => self.stats.unauthenticated += 1,
+            Err(AuthError::Replayed) => self.stats.replayed += 1,
+            Ok(_) => {}
+        }
+        result
+    }
+
+    fn verify(&mut self, header: &[u8], data: &[u8]) -> std::result::Result<Vec<u8>, AuthError> {
+        let envelope: Envelope = crate::wire::deserialize(data)
+            .map_err(|_| AuthError::Unauthenticated)?;
+        self.mac(header, envelope.timestamp, envelope.nonce, &envelope.payload)
+            .verify_slice(&envelope.mac)
+            .map_err(|_| AuthError::Unauthenticated)?;
+        let now = self.clock.now();
+        let sent = UNIX_EPOCH + Duration::from_millis(envelope.timestamp);
+        let skew = now.duration_since(sent)
+            .or_else(|_| sent.duration_since(now))
+            .unwrap_or_default();
+        if skew > self.max_skew {
+            return Err(AuthError::Replayed);
+        }
+        // Anything older than the skew window is already rejected above,
+        // so nonces only need to be remembered for that long.
+        let max_skew = self.max_skew;
+        self.seen.retain(|_, seen| now.duration_since(*seen).unwrap_or_default() <= max_skew * 2);
+        if self.seen.insert(envelope.nonce, now).is_some() {
+            return Err(AuthError::Replayed);
+        }
+        Ok(envelope.payload)
+    }
+}
@@ -0,0 +1,163 @@
+//! Recordings of bootstrap traffic, one JSON object per line, so that an
+//! incident can be inspected with ordinary tools and replayed later.
+
+use crate::election::Message;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// The `[capture]` table of the bootstrap config. When present, the
+/// daemon records every message it sends and receives.
+///
+/// ```toml
+/// [capture]
+/// path = "/var/log/homesec/capture.jsonl"
+/// max_bytes = 10485760
+/// keep = 5
+/// ```
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CaptureConfig {
+    pub path: String,
+    /// Size at which the capture is rotated to `<path>.1`.
+    pub max_bytes: u64,
+    /// Number of rotated captures kept besides the current one.
+    pub keep: usize,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            path: String::from("/var/log/homesec/capture.jsonl"),
+            max_bytes: 10 * 1024 * 1024,
+            keep: 5,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CaptureRecord {
+    /// Milliseconds since the unix epoch.
+    pub time: u64,
+    pub direction: Direction,
+    /// Source of a received message, or destination of a sent one.
+    pub addr: SocketAddr,
+    pub message: Message,
+}
+
+impl CaptureRecord {
+    pub fn new(time: SystemTime, direction: Direction, addr: SocketAddr, message: Message) -> Self {
+        Self {
+            time: time.duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0),
+            direction,
+            addr,
+            message,
+        }
+    }
+
+    pub fn system_time(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_millis(self.time)
+    }
+}
+
+pub struct CaptureWriter {
+    path: PathBuf,
+    out: BufWriter<File>,
+    written: u64,
+    /// `max_bytes` and `keep`, if the capture is rotated.
+    rotation: Option<(u64, usize)>,
+}
+
+fn append<P: AsRef<Path>>(path: P) -> Result<(BufWriter<File>, u64)> {
+    let file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    let len = file.metadata()?.len();
+    Ok((BufWriter::new(file), len))
+}
+
+impl CaptureWriter {
+    /// Appends to the capture at `path`, creating it if needed.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let (out, written) = append(&path)?;
+        Ok(Self {
+            path,
+            out,
+            written,
+            rotation: None,
+        })
+    }
+
+    /// Appends to the capture in `config`, moving it to `<path>.1` once it
+    /// grows past `max_bytes`, and older ones to `<path>.2` and so on.
+    pub fn rotating(config: &CaptureConfig) -> Result<Self> {
+        if let Some(dir) = Path::new(&config.path).parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut writer = Self::open(&config.path)?;
+        writer.rotation = Some((config.max_bytes, config.keep));
+        Ok(writer)
+    }
+
+    /// Writes `record` on its own line. Every record is flushed, so that
+    /// a capture survives the process being killed.
+    pub fn write(&mut self, record: &CaptureRecord) -> Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        self.out.write_all(&line)?;
+        self.out.flush()?;
+        self.written += line.len() as u64;
+        match self.rotation {
+            Some((max_bytes, keep)) if self.written >= max_bytes => self.rotate(keep),
+            _ => Ok(()),
+        }
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn rotate(&mut self, keep: usize) -> Result<()> {
+        if keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..keep).rev() {
+                if self.rotated(n).exists() {
+                    std::fs::rename(self.rotated(n), self.rotated(n + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, self.rotated(1))?;
+        }
+        let (out, written) = append(&self.path)?;
+        self.out = out;
+        self.written = written;
+        Ok(())
+    }
+}
+
+/// Reads every record in the capture at `path`, in order.
+pub fn read_capture<P: AsRef<Path>>(path: P) -> Result<Vec<CaptureRecord>> {
+    let path = path.as_ref();
+    let mut records = Vec::new();
+    for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        records.push(serde_json::from_str(&line)
+            .map_err(|e| anyhow!("{}:{}: invalid capture record: {}", path.display(), i + 1, e))?);
+    }
+    Ok(records)
+}
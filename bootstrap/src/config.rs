@@ -0,0 +1,151 @@
+use anyhow::{anyhow, Result};
+use crate::capture::CaptureConfig;
+use crate::hooks::HooksConfig;
+use crate::installer::InstallerConfig;
+use crate::multicast::DEFAULT_MULTICAST_GROUP_V4;
+use crate::timing::TimingConfig;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use std::path::Path;
+
+pub const CONFIG_PATH: &str = "/etc/homesec/bootstrap.toml";
+
+/// How nodes find each other.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Discovery {
+    /// IPv4 directed broadcast on each selected interface.
+    Broadcast,
+    /// `multicast_group`, for routers that filter broadcast.
+    MulticastV4,
+    /// The link-local `MULTICAST_GROUP_V6`, for IPv6-only networks.
+    MulticastV6,
+}
+
+/// Per-node settings read from `CONFIG_PATH`. Every field is optional, so
+/// a missing or empty file yields the defaults.
+///
+/// ```toml
+/// # Pi 4 with an SSD: preferred over every node with a lower priority.
+/// priority = 10
+/// master_eligible = true
+///
+/// # Five etcd servers to survive two failures.
+/// server_count = 5
+///
+/// # Six Pis: with fewer than four in reach, do not elect a master.
+/// cluster_size = 6
+///
+/// # Broadcast on both the wired and wireless networks.
+/// interfaces = ["eth0", "wlan0"]
+///
+/// # The mesh router drops broadcast.
+/// discovery = "multicast-v4"
+/// multicast_group = "239.255.43.1"
+///
+/// [labels]
+/// "homesec/storage" = "ssd"
+///
+/// [installer]
+/// distribution = "k3s"
+///
+/// # Record traffic for `homesec-bootstrap replay`.
+/// [capture]
+///
+/// # Give slow nodes longer to be heard.
+/// [timing]
+/// node_timeout = "60s"
+///
+/// # Name the node after its hid once it is in the cluster.
+/// [hooks]
+/// on_joined_as_agent = "/etc/homesec/hooks/set-hostname"
+/// on_joined_as_server = "/etc/homesec/hooks/set-hostname"
+/// ```
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BootstrapConfig {
+    /// Preference for this node as master or server. Higher wins; the
+    /// random election priority only breaks ties.
+    pub priority: i32,
+    /// Whether this node may be elected master or chosen as a server.
+    pub master_eligible: bool,
+    /// Servers, the master included, that the master picks to run the
+    /// embedded etcd. `SERVER_COUNT` overrides it.
+    pub server_count: usize,
+    /// Nodes the cluster is meant to have. When set, electing a master
+    /// takes votes from a majority of them, so that the two sides of a
+    /// partition cannot both elect one. Unset, only the nodes still heard
+    /// from count, which keeps elections going however many are lost.
+    pub cluster_size: Option<usize>,
+    /// Kubernetes labels applied to this node when it joins the cluster.
+    pub labels: BTreeMap<String, String>,
+    /// Interfaces to broadcast on. Empty means the first interface with
+    /// an IPv4 address.
+    pub interfaces: Vec<String>,
+    pub discovery: Discovery,
+    /// IPv4 group used by `Discovery::MulticastV4`.
+    pub multicast_group: Ipv4Addr,
+    /// Whether to publish the master over mDNS and fall back to browsing
+    /// for it when its announcements do not arrive.
+    pub mdns: bool,
+    pub installer: InstallerConfig,
+    pub capture: Option<CaptureConfig>,
+    pub timing: TimingConfig,
+    pub hooks: HooksConfig,
+}
+
+impl Default for BootstrapConfig {
+    fn default() -> Self {
+        Self {
+            priority: 0,
+            master_eligible: true,
+            server_count: 3,
+            cluster_size: None,
+            labels: BTreeMap::new(),
+            interfaces: Vec::new(),
+            discovery: Discovery::Broadcast,
+            multicast_group: DEFAULT_MULTICAST_GROUP_V4,
+            mdns: true,
+            installer: InstallerConfig::default(),
+            capture: None,
+            timing: TimingConfig::default(),
+            hooks: HooksConfig::default(),
+        }
+    }
+}
+
+impl BootstrapConfig {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        Self::parse(&std::fs::read_to_string(path)?)
+            .map_err(|e| anyhow!("invalid config at {}: {}", path.display(), e))
+    }
+
+    pub fn parse(s: &str) -> Result<Self> {
+        let config: Self = toml::from_str(s)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Rejects settings the daemon could not work with.
+    pub fn validate(&self) -> Result<()> {
+        if self.server_count == 0 {
+            return Err(anyhow!("server_count must be at least 1"));
+        }
+        if self.cluster_size == Some(0) {
+            return Err(anyhow!("cluster_size must be at least 1"));
+        }
+        self.timing.validate()?;
+        if self.hooks.timeout.is_zero() || self.hooks.timeout >= self.timing.master_timeout {
+            return Err(anyhow!(
+                "hooks.timeout ({:?}) must be greater than zero and shorter than timing.master_timeout ({:?})",
+                self.hooks.timeout, self.timing.master_timeout
+            ));
+        }
+        Ok(())
+    }
+}
@@ -1,46 +1,156 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::time::{Duration, SystemTime};
 use anyhow::{anyhow, Result};
 use serde::{Serialize, Deserialize};
-use std::cmp::Ordering;
-use std::collections::HashSet;
-use std::ops::Sub;
-use std::alloc::System;
-use rand::prelude::*;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, Mutex};
+use rand::{RngCore, SeedableRng};
+use rand::rngs::StdRng;
 use uuid::Uuid;
+use crate::artifacts::ArtifactSource;
+use crate::state::Role;
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Source of the current time for an `Election`. Injected so that tests
+/// can drive an election forward without sleeping through its delays.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// Wall clock used by the daemon.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Clock that only moves when told to. Clones share the same time, so a
+/// single instance can drive every node in a simulation.
+#[derive(Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<SystemTime>>,
+}
+
+impl ManualClock {
+    pub fn new(start: SystemTime) -> Self {
+        Self {
+            now: Arc::new(Mutex::new(start)),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+
+    pub fn set(&self, time: SystemTime) {
+        *self.now.lock().unwrap() = time;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct AppearanceMessage {
+    /// Random priority, used only to break ties in `static_priority`.
     pub priority: i32,
     pub is_master: bool,
     pub hid: Uuid,
+    /// Configured preference for this node, see `BootstrapConfig`.
+    pub static_priority: i32,
+    pub master_eligible: bool,
+    pub labels: BTreeMap<String, String>,
+    /// The sender's current term, so that nodes behind it catch up.
+    pub term: u64,
+    /// Role the sender last joined the cluster in, if it has.
+    pub role: Option<Role>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Periodic announcement from an installed master. The k3s token is not
+/// included; agents obtain it with a `JoinRequest`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct ConnectionDetails {
     pub hid: Uuid,
-    pub token: String,
+    /// Nodes chosen to run the control plane, starting with the master
+    /// itself. Every other node joins as an agent.
+    pub servers: Vec<Uuid>,
+    /// Set if the master serves an artifact cache for offline installs.
+    pub artifacts: Option<ArtifactSource>,
+    /// Term the master was elected in. Every announcement renews its lease
+    /// for that term, and nodes follow the master with the highest one.
+    pub term: u64,
+    /// Address of the master's Kubernetes API, if the address its
+    /// announcements come from cannot be used, as with IPv6 link-local.
+    pub api_addr: Option<IpAddr>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct JoinRequest {
+    pub hid: Uuid,
+}
+
+/// Unicast reply to a `JoinRequest`. `token` is the k3s node token
+/// encrypted to the requesting agent, see `Authenticator::seal_token`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct JoinResponse {
+    pub hid: Uuid,
+    pub master: Uuid,
+    pub nonce: [u8; 12],
+    pub token: Vec<u8>,
+}
+
+/// A node votes at most once per term, and a vote only counts in the
+/// term it was cast in.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct CastVote {
     pub addr: SocketAddr,
     pub hid: Uuid,
+    pub term: u64,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct ElectionResult {
     pub addr: SocketAddr,
     pub hid: Uuid,
+    pub term: u64,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Announces that `hid` is leaving the cluster, so peers forget it. A
+/// master repeats it for a node once it has drained and deleted that
+/// node's Kubernetes Node, which the leaving node waits for.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LeaveMessage {
+    pub hid: Uuid,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum Message {
     Appearance(AppearanceMessage),
     CastVote(CastVote),
+    /// Asks every node to start a new term.
     Reset,
     ElectionResult(ElectionResult),
     ConnectionDetails(ConnectionDetails),
+    JoinRequest(JoinRequest),
+    JoinResponse(JoinResponse),
+    Leave(LeaveMessage),
+}
+
+impl Message {
+    /// Term the message was sent in, for the kinds that carry one.
+    pub fn term(&self) -> Option<u64> {
+        match self {
+            Message::Appearance(m) => Some(m.term),
+            Message::CastVote(m) => Some(m.term),
+            Message::ElectionResult(m) => Some(m.term),
+            Message::ConnectionDetails(m) => Some(m.term),
+            Message::Reset | Message::JoinRequest(_) | Message::JoinResponse(_) | Message::Leave(_) => None,
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -49,178 +159,359 @@ pub struct Node {
     pub hid: Uuid,
     pub is_master: bool,
     pub priority: i32,
+    pub static_priority: i32,
+    pub master_eligible: bool,
+    pub labels: BTreeMap<String, String>,
+    pub role: Option<Role>,
     pub last_seen: SystemTime,
+    /// Nodes that voted for this one in the current term.
     pub votes: HashSet<SocketAddr>,
 }
 
 impl Node {
-    fn from_appearance(addr: SocketAddr, msg: &AppearanceMessage) -> Self {
+    fn from_appearance(addr: SocketAddr, msg: &AppearanceMessage, now: SystemTime) -> Self {
         Self {
             addr,
             hid: msg.hid,
             is_master: msg.is_master,
             priority: msg.priority,
-            last_seen: SystemTime::now(),
+            static_priority: msg.static_priority,
+            master_eligible: msg.master_eligible,
+            labels: msg.labels.clone(),
+            role: msg.role,
+            last_seen: now,
             votes: HashSet::new(),
         }
     }
 
-    fn process_appearance(&mut self, msg: &AppearanceMessage) -> Result<()> {
+    fn process_appearance(&mut self, msg: &AppearanceMessage, now: SystemTime) -> Result<()> {
         if self.hid != msg.hid {
             return Err(anyhow!("detected address change for {}", msg.hid));
         }
         self.priority = msg.priority;
         self.is_master = msg.is_master;
-        self.last_seen = SystemTime::now();
+        self.static_priority = msg.static_priority;
+        self.master_eligible = msg.master_eligible;
+        self.labels = msg.labels.clone();
+        self.role = msg.role;
+        self.last_seen = now;
         Ok(())
     }
 
-    fn cast_vote(&mut self, voter: SocketAddr) {
-        self.votes.insert(voter);
+    /// Whether the node runs the control plane, and so holds etcd state
+    /// that a new master must not start over from.
+    pub fn is_server(&self) -> bool {
+        matches!(self.role, Some(Role::Master) | Some(Role::Server))
+    }
+
+    /// Counts `voter`, returning false if it had already voted.
+    fn cast_vote(&mut self, voter: SocketAddr) -> bool {
+        self.votes.insert(voter)
     }
 }
 
+/// Default silence after which a node is evicted from an election.
+pub const DEFAULT_NODE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default time after the start of an election, and between votes,
+/// during which no vote is cast.
+pub const DEFAULT_ELECTION_DELAY: Duration = Duration::from_secs(10);
+
+/// Elects a master by quorum, one term at a time. Each node votes once
+/// per term for the best ranked candidate, or for an existing master. A
+/// term that stalls without a quorum, or a message from a later term,
+/// moves the node on to a new term in which it votes again, so a split
+/// never needs a reset. Votes are repeated until the term concludes, so
+/// a lost one only delays it. Only live nodes count toward the quorum,
+/// unless `cluster_size` asks for a majority of the whole cluster too,
+/// which keeps both sides of a partition from electing a master. The
+/// winner holds a lease for its
+/// term that its announcements renew, and a lease from a later term
+/// always supersedes one from an earlier term.
 pub struct Election {
     pub nodes: Vec<Node>,
+    /// When the current term started.
     pub start_time: SystemTime,
     pub last_vote: SystemTime,
-    pub voted: bool,
+    pub term: u64,
+    /// Candidate this node voted for in the current term.
+    pub voted_for: Option<(SocketAddr, Uuid)>,
+    /// Master announcing a lease for the current term.
+    pub leader: Option<(SocketAddr, Uuid)>,
     pub delay: Duration,
+    /// How long a node may stay silent before it is dropped from the
+    /// membership list and no longer counts toward quorum.
+    pub node_timeout: Duration,
+    /// Number of nodes the cluster is configured to have, of which the
+    /// quorum must then be a majority.
+    pub cluster_size: Option<usize>,
     pub priority: i32,
+    clock: Arc<dyn Clock>,
+    rng: Box<dyn RngCore + Send>,
 }
 
-fn gen_priority() -> i32 {
-    rand::random::<u16>() as _
+fn gen_priority(rng: &mut dyn RngCore) -> i32 {
+    (rng.next_u32() as u16) as _
+}
+
+impl Default for Election {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Election {
     pub fn new() -> Self {
+        Self::with_clock(Arc::new(SystemClock), Box::new(StdRng::from_entropy()))
+    }
+
+    /// Creates an election that reads time from `clock` and draws its
+    /// priority in every term from `rng`. Seeding the rng and sharing a `ManualClock`
+    /// makes a run fully reproducible.
+    pub fn with_clock(clock: Arc<dyn Clock>, mut rng: Box<dyn RngCore + Send>) -> Self {
+        let now = clock.now();
+        let priority = gen_priority(rng.as_mut());
         Self {
             nodes: Vec::new(),
-            start_time: SystemTime::now(),
-            last_vote: SystemTime::now(),
-            voted: false,
-            delay: Duration::from_secs(10),
-            priority: gen_priority(),
+            start_time: now,
+            last_vote: now,
+            term: 0,
+            voted_for: None,
+            leader: None,
+            delay: DEFAULT_ELECTION_DELAY,
+            node_timeout: DEFAULT_NODE_TIMEOUT,
+            cluster_size: None,
+            priority,
+            clock,
+            rng,
         }
     }
 
+    fn elapsed_since(&self, time: SystemTime) -> Duration {
+        self.clock.now().duration_since(time).unwrap_or_default()
+    }
+
     pub fn process_message(&mut self, source: SocketAddr, msg: &Message) -> Result<()> {
+        if let Some(term) = msg.term() {
+            if term > self.term {
+                self.enter_term(term);
+            }
+        }
+        // Messages from an earlier term still show that their sender is
+        // alive, but neither its votes nor its lease count any more.
+        let current = msg.term().is_none_or(|term| term == self.term);
         match msg {
-            Message::Appearance(msg) => self.handle_appearance(source, &msg)?,
-            Message::CastVote(CastVote { addr, hid }) => self.cast_vote(*addr, *hid, source)?,
+            Message::Appearance(msg) => self.handle_appearance(source, msg)?,
+            Message::CastVote(CastVote { addr, hid, .. }) if current => self.cast_vote(*addr, *hid, source)?,
             Message::Reset => {
-                println!("resetting election");
-                self.reset()
+                println!("{} asked for a new election", source);
+                self.enter_term(self.term + 1);
             }
-            Message::ElectionResult(ElectionResult { addr, hid }) => {
-                println!("{}, hid={} elected master by {}", addr, hid, source);
+            Message::ElectionResult(ElectionResult { addr, hid, term }) if current => {
+                println!("{}, hid={} elected master in term {} by {}", addr, hid, term, source);
             },
-            Message::ConnectionDetails(ConnectionDetails{ hid, token }) => {
-                println!("received connection details for {}", hid);
+            Message::ConnectionDetails(ConnectionDetails { hid, term, .. }) if current => {
+                println!("received connection details for {} in term {}", hid, term);
+                if self.leader.is_some_and(|(_, leader)| leader != *hid) {
+                    // Only nodes that disagreed on the membership can elect
+                    // two masters. Neither lease can be trusted, so move on.
+                    println!("{} announced a second lease for term {}", hid, term);
+                    self.enter_term(term + 1);
+                    return Ok(());
+                }
+                self.leader = Some((source, *hid));
+                let now = self.clock.now();
                 if let Some(node) = self.nodes.iter_mut().find(|node| node.hid == *hid) {
                     node.is_master = true;
+                    node.last_seen = now;
                 } else {
                     self.nodes.push(Node::from_appearance(source, &AppearanceMessage{
                         is_master: true,
                         hid: *hid,
                         priority: -1,
-                    }));
+                        static_priority: 0,
+                        master_eligible: true,
+                        labels: BTreeMap::new(),
+                        term: *term,
+                        role: Some(Role::Master),
+                    }, now));
                 }
             },
+            Message::CastVote(_) | Message::ElectionResult(_) | Message::ConnectionDetails(_) => {
+                println!("ignoring {} from {} for earlier term", msg.name(), source);
+            }
+            Message::JoinRequest(_) | Message::JoinResponse(_) => {}
+            Message::Leave(LeaveMessage { hid }) => {
+                println!("{}, hid={} left the cluster", source, hid);
+                self.remove_nodes(|node| node.hid == *hid);
+            }
         }
         Ok(())
     }
 
+    /// Moves on to `term`, discarding the votes and lease of the previous
+    /// one. Voting waits out `delay` again, so that nodes that had stopped
+    /// announcing themselves are heard before anyone counts a quorum. A
+    /// fresh priority keeps candidates that tied from splitting the vote
+    /// the same way again.
+    pub fn enter_term(&mut self, term: u64) {
+        println!("entering term {}", term);
+        let now = self.clock.now();
+        self.term = term;
+        self.priority = gen_priority(self.rng.as_mut());
+        println!("assigned priority {}", self.priority);
+        self.start_time = now;
+        self.last_vote = now;
+        self.voted_for = None;
+        self.leader = None;
+        for node in self.nodes.iter_mut() {
+            node.votes.clear();
+            node.is_master = false;
+        }
+    }
+
+    /// Removes the nodes matching `pred` along with any votes they cast,
+    /// returning them.
+    fn remove_nodes<F: Fn(&Node) -> bool>(&mut self, pred: F) -> Vec<Node> {
+        let (removed, kept): (Vec<_>, Vec<_>) = self.nodes.drain(..).partition(|node| pred(node));
+        self.nodes = kept;
+        for node in &removed {
+            for candidate in self.nodes.iter_mut() {
+                candidate.votes.remove(&node.addr);
+            }
+        }
+        removed
+    }
+
+    /// Drops every node that has not been heard from within `node_timeout`,
+    /// along with any votes it cast.
+    pub fn expire_nodes(&mut self) {
+        let now = self.clock.now();
+        let timeout = self.node_timeout;
+        let expired = self.remove_nodes(|node| now.duration_since(node.last_seen).unwrap_or_default() > timeout);
+        for node in &expired {
+            println!("evicting {}, hid={} after {:?} of silence", node.addr, node.hid, timeout);
+            if self.leader.is_some_and(|(_, hid)| hid == node.hid) {
+                self.leader = None;
+            }
+        }
+    }
+
+    /// Two thirds of the live nodes, and at least a majority of
+    /// `cluster_size` when it is set.
     fn quorum(&self) -> usize {
-        (self.nodes.len() as f64 * 0.666666666666667).ceil() as _
+        let live = (self.nodes.len() as f64 * 0.666666666666667).ceil() as usize;
+        self.cluster_size.map_or(live, |size| live.max(size / 2 + 1))
     }
 
     fn too_early(&self) -> bool {
-        SystemTime::now().duration_since(self.start_time).unwrap() < self.delay
+        self.elapsed_since(self.start_time) < self.delay
+    }
+
+    /// Best ranked of the nodes that claim to be master, either by their
+    /// lease or, while rejoining, in their appearances.
+    fn existing_master(&self) -> Option<(SocketAddr, Uuid)> {
+        self.nodes.iter()
+            .filter(|node| node.is_master)
+            .max_by_key(|node| (node.static_priority, node.priority))
+            .map(|node| (node.addr, node.hid))
     }
 
     pub fn check_vote(&mut self) -> Option<(SocketAddr, Uuid)> {
-        if self.voted {
-            None
-        } else if let Some(node) = self.nodes.iter().find(|node| node.is_master) {
-            // Always prefer existing master
-            self.voted = true;
-            Some((node.addr, node.hid))
-        } else if self.nodes.is_empty() || self.too_early() {
-            None
-        } else {
-            let mut nodes = self.nodes.clone();
-            nodes.sort_by_key(|node| node.priority);
-            self.voted = true;
-            let node = nodes.last().unwrap();
-            Some((node.addr, node.hid))
-        }
-    }
-
-    pub fn check_result(&mut self) -> (Option<(SocketAddr, Uuid)>, bool) {
-        if let Some(node) = self.nodes.iter().find(|node| node.is_master) {
-            // always prefer an existing master
-            return (Some((node.addr, node.hid)), false);
-        }
-        if self.too_early() || SystemTime::now().duration_since(self.last_vote).unwrap() < self.delay {
+        self.expire_nodes();
+        if self.voted_for.is_some() {
+            return None;
+        }
+        // Always prefer an existing master
+        let candidate = match self.existing_master() {
+            Some(master) => Some(master),
+            None if self.nodes.is_empty() || self.too_early() => None,
+            None => self.ranking().first().copied(),
+        };
+        if candidate.is_some() {
+            self.voted_for = candidate;
+            self.last_vote = self.clock.now();
+        }
+        candidate
+    }
+
+    /// Eligible nodes from most to least preferred as a master. Once there
+    /// are servers only they stand, so that the control plane outlives its
+    /// master; the configured priority wins and the random priority breaks
+    /// ties.
+    pub fn ranking(&self) -> Vec<(SocketAddr, Uuid)> {
+        let mut nodes = self.nodes.iter()
+            .filter(|node| node.master_eligible)
+            .collect::<Vec<_>>();
+        if nodes.iter().any(|node| node.is_server()) {
+            nodes.retain(|node| node.is_server());
+        }
+        nodes.sort_by_key(|node| std::cmp::Reverse((node.static_priority, node.priority)));
+        nodes.into_iter().map(|node| (node.addr, node.hid)).collect()
+    }
+
+    /// Nodes that already run the control plane.
+    pub fn servers(&self) -> Vec<Uuid> {
+        self.nodes.iter().filter(|node| node.is_server()).map(|node| node.hid).collect()
+    }
+
+    pub fn check_result(&mut self) -> Option<(SocketAddr, Uuid)> {
+        self.expire_nodes();
+        if let Some(leader) = self.leader {
+            // A lease is only announced for a term its holder won.
+            return Some(leader);
+        }
+        if self.too_early() || self.elapsed_since(self.last_vote) < self.delay {
             // wait for sufficient appearance messages
-            return (None, false);
+            return None;
         }
         let quorum = self.quorum();
         let nodes = self.nodes.iter()
             .filter(|node| node.votes.len() >= quorum)
             .collect::<Vec<_>>();
-        if nodes.is_empty() {
-            // No quorum has yet been made
-            return (None, false);
-        }
-        let acc = (nodes[0].addr, nodes[0].hid, nodes[0].votes.len());
-        let (addr, hid, winning_vote_count) = nodes[1..]
-            .iter()
-            .fold(acc, |acc, node| {
-                if node.votes.len() > acc.2 {
-                    (node.addr, node.hid, node.votes.len())
-                } else {
-                    acc
-                }
-            });
-        if nodes.iter().filter(|node| {
-            node.votes.len() == winning_vote_count
-        }).count() > 1 {
-            println!("More than one master was elected. Holding new election...");
-            self.reset();
-            return (None, true);
+        if let [node] = nodes[..] {
+            return Some((node.addr, node.hid));
+        }
+        // Nodes that disagree on the membership may split the vote, and a
+        // lost vote may leave every candidate short. Either way the votes
+        // of this term will not change, so start another.
+        if self.voted_for.is_some() && self.elapsed_since(self.last_vote) >= self.delay * 2 {
+            println!("no quorum in term {}", self.term);
+            self.enter_term(self.term + 1);
         }
-        (Some((addr, hid)), false)
+        None
     }
 
     pub fn handle_appearance(&mut self, addr: SocketAddr, msg: &AppearanceMessage) -> Result<()> {
         println!("{} {:?}", addr, msg);
-        match self.nodes.iter_mut().find(|n| n.addr == addr) {
-            Some(node) => node.process_appearance(msg)?,
-            None => self.nodes.push(Node::from_appearance(addr, msg)),
-        }
+        let now = self.clock.now();
+        let node = match self.nodes.iter_mut().find(|n| n.addr == addr) {
+            Some(node) => {
+                node.process_appearance(msg, now)?;
+                node
+            }
+            None => {
+                self.nodes.push(Node::from_appearance(addr, msg, now));
+                self.nodes.last_mut().unwrap()
+            }
+        };
+        // A master that has fallen behind lost its lease to a later term.
+        node.is_master &= msg.term == self.term;
         Ok(())
     }
 
     pub fn cast_vote(&mut self, addr: SocketAddr, hid: Uuid, voter: SocketAddr) -> Result<()> {
         match self.nodes.iter_mut().find(|n| n.addr == addr && n.hid == hid) {
             Some(node) => {
-                node.cast_vote(voter);
-                self.last_vote = SystemTime::now();
-                println!("{} casted vote for {}, total_votes={}", voter, addr, node.votes.len());
+                // A repeated vote changes nothing, so it does not hold
+                // back the count either.
+                if node.cast_vote(voter) {
+                    self.last_vote = self.clock.now();
+                    println!("{} casted vote for {}, total_votes={}", voter, addr, node.votes.len());
+                }
                 Ok(())
             }
             None => Err(anyhow!("cannot cast vote on unknown candidate node")),
         }
     }
-
-    pub fn reset(&mut self) {
-        self.nodes.clear();
-        self.start_time = SystemTime::now();
-        self.voted = false;
-        self.priority = gen_priority();
-        println!("assigned priority {}", self.priority);
-    }
 }
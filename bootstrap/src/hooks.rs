@@ -0,0 +1,216 @@
+//! Actions run when this node's place in the cluster changes, such as
+//! setting the hostname or labelling the node, without patching the
+//! daemon.
+
+use crate::state::Role;
+use crate::timing::duration;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::io::{ErrorKind, Write};
+use std::net::SocketAddr;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// The `[hooks]` table of the bootstrap config. Each hook is an
+/// executable run with the event in `HOMESEC_*` environment variables
+/// and as JSON on stdin, see `HookEvent`.
+///
+/// ```toml
+/// [hooks]
+/// on_elected_master = "/etc/homesec/hooks/set-hostname"
+/// on_joined_as_agent = "/etc/homesec/hooks/set-hostname"
+/// on_joined_as_server = "/etc/homesec/hooks/set-hostname"
+/// timeout = "10s"
+/// ```
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HooksConfig {
+    pub on_elected_master: Option<String>,
+    pub on_joined_as_agent: Option<String>,
+    pub on_joined_as_server: Option<String>,
+    pub on_master_lost: Option<String>,
+    pub on_removed: Option<String>,
+    /// How long a hook may run before it is killed and reported as failed.
+    /// Hooks run one at a time, so it must be shorter than
+    /// `timing.master_timeout` for events not to pile up behind a hung one.
+    #[serde(deserialize_with = "duration")]
+    pub timeout: Duration,
+}
+
+impl Default for HooksConfig {
+    fn default() -> Self {
+        Self {
+            on_elected_master: None,
+            on_joined_as_agent: None,
+            on_joined_as_server: None,
+            on_master_lost: None,
+            on_removed: None,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum HookKind {
+    /// This node started serving as master for a term.
+    ElectedMaster,
+    /// This node joined a master's cluster as an agent.
+    JoinedAsAgent,
+    /// This node joined a master's cluster as an additional server.
+    JoinedAsServer,
+    /// The master this node followed stopped renewing its lease, or left.
+    MasterLost,
+    /// This node left the cluster and was uninstalled.
+    Removed,
+}
+
+impl HookKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HookKind::ElectedMaster => "on_elected_master",
+            HookKind::JoinedAsAgent => "on_joined_as_agent",
+            HookKind::JoinedAsServer => "on_joined_as_server",
+            HookKind::MasterLost => "on_master_lost",
+            HookKind::Removed => "on_removed",
+        }
+    }
+}
+
+/// What a hook is told about an event.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct HookEvent {
+    pub event: HookKind,
+    /// This node.
+    pub hid: Uuid,
+    /// Role this node has after the event, or had before it was removed.
+    pub role: Option<Role>,
+    /// The master the event concerns.
+    pub master_hid: Option<Uuid>,
+    /// Bootstrap address of that master, as this node hears it.
+    pub master_addr: Option<SocketAddr>,
+    pub term: u64,
+}
+
+impl HookEvent {
+    /// The event as environment variables. Unset fields are empty.
+    pub fn env(&self) -> Vec<(&'static str, String)> {
+        let or_empty = |value: Option<String>| value.unwrap_or_default();
+        vec![
+            ("HOMESEC_EVENT", self.event.name().to_string()),
+            ("HOMESEC_HID", self.hid.to_string()),
+            ("HOMESEC_ROLE", or_empty(self.role.map(|role| role.as_str().to_string()))),
+            ("HOMESEC_MASTER_HID", or_empty(self.master_hid.map(|hid| hid.to_string()))),
+            ("HOMESEC_MASTER_ADDR", or_empty(self.master_addr.map(|addr| addr.to_string()))),
+            ("HOMESEC_TERM", self.term.to_string()),
+        ]
+    }
+}
+
+/// Reactions to `HookEvent`s. Every method does nothing by default, so
+/// an implementation only overrides the events it cares about.
+pub trait Hooks {
+    fn on_elected_master(&self, _event: &HookEvent) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_joined_as_agent(&self, _event: &HookEvent) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_joined_as_server(&self, _event: &HookEvent) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_master_lost(&self, _event: &HookEvent) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_removed(&self, _event: &HookEvent) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs the hook for `event`. A failure is reported rather than returned,
+/// since a broken hook must not take the node out of the cluster.
+pub fn fire_hook(hooks: &dyn Hooks, event: &HookEvent) {
+    let result = match event.event {
+        HookKind::ElectedMaster => hooks.on_elected_master(event),
+        HookKind::JoinedAsAgent => hooks.on_joined_as_agent(event),
+        HookKind::JoinedAsServer => hooks.on_joined_as_server(event),
+        HookKind::MasterLost => hooks.on_master_lost(event),
+        HookKind::Removed => hooks.on_removed(event),
+    };
+    if let Err(e) = result {
+        println!("hook {} failed: {}", event.event.name(), e);
+    }
+}
+
+/// Runs the executables named in a `HooksConfig`.
+pub struct ExecHooks {
+    pub config: HooksConfig,
+}
+
+impl ExecHooks {
+    pub fn new(config: HooksConfig) -> Self {
+        Self { config }
+    }
+
+    fn run(&self, path: &Option<String>, event: &HookEvent) -> Result<()> {
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        println!("running hook {}: {}", event.event.name(), path);
+        let mut child = Command::new(path)
+            .envs(event.env())
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|e| anyhow!("failed to run {}: {}", path, e))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            match stdin.write_all(&serde_json::to_vec(event)?) {
+                // Hooks that only read the environment may exit first.
+                Err(e) if e.kind() == ErrorKind::BrokenPipe => {}
+                result => result?,
+            }
+        }
+        let deadline = Instant::now() + self.config.timeout;
+        loop {
+            if let Some(status) = child.try_wait()? {
+                if status.success() {
+                    return Ok(());
+                }
+                return Err(anyhow!("{} exited with {}", path, status));
+            }
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(anyhow!("{} was killed after running for {:?}", path, self.config.timeout));
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+impl Hooks for ExecHooks {
+    fn on_elected_master(&self, event: &HookEvent) -> Result<()> {
+        self.run(&self.config.on_elected_master, event)
+    }
+
+    fn on_joined_as_agent(&self, event: &HookEvent) -> Result<()> {
+        self.run(&self.config.on_joined_as_agent, event)
+    }
+
+    fn on_joined_as_server(&self, event: &HookEvent) -> Result<()> {
+        self.run(&self.config.on_joined_as_server, event)
+    }
+
+    fn on_master_lost(&self, event: &HookEvent) -> Result<()> {
+        self.run(&self.config.on_master_lost, event)
+    }
+
+    fn on_removed(&self, event: &HookEvent) -> Result<()> {
+        self.run(&self.config.on_removed, event)
+    }
+}
@@ -0,0 +1,552 @@
+//! Installs the Kubernetes distribution a node runs once its role is
+//! known. The daemon only decides who joins whom; everything specific to
+//! a distribution lives behind `Installer`.
+
+use crate::artifacts::{ArtifactCache, DEFAULT_ARTIFACT_PORT};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::process::Command;
+
+/// Port of the Kubernetes API on every server.
+pub const API_PORT: u16 = 6443;
+
+/// Where a node joins an existing cluster.
+pub struct Join<'a> {
+    /// Any server already in the cluster.
+    pub server: IpAddr,
+    pub token: &'a str,
+}
+
+impl Join<'_> {
+    pub fn url(&self) -> String {
+        format!("https://{}", SocketAddr::new(self.server, API_PORT))
+    }
+}
+
+pub trait Installer {
+    /// Installs a control plane node, initializing a new cluster if
+    /// `join` is `None`. An agent already installed on this node is
+    /// replaced.
+    fn install_server(&self, join: Option<&Join>) -> Result<()>;
+
+    fn install_agent(&self, join: &Join) -> Result<()>;
+
+    fn uninstall(&self) -> Result<()>;
+
+    fn is_installed(&self) -> bool;
+
+    /// Version of the installed distribution, if it can be determined.
+    fn version(&self) -> Option<String>;
+
+    /// Token another node needs to join this server, as a server if
+    /// `server` is set and as an agent otherwise.
+    fn join_token(&self, server: bool) -> Result<String>;
+
+    /// kubectl bundled with the distribution, configured for the local
+    /// server. Agents have no credentials for it.
+    fn kubectl(&self) -> Command {
+        Command::new("kubectl")
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Distribution {
+    K3s,
+    K0s,
+    /// Kubernetes is installed and joined by other means; the daemon only
+    /// elects and announces.
+    External,
+    /// Nothing is installed, and every call is recorded to `record_path`.
+    /// For running daemons in tests.
+    Stub,
+}
+
+impl Distribution {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Distribution::K3s => "k3s",
+            Distribution::K0s => "k0s",
+            Distribution::External => "external",
+            Distribution::Stub => "stub",
+        }
+    }
+}
+
+/// The `[installer]` table of the bootstrap config.
+///
+/// ```toml
+/// [installer]
+/// distribution = "k3s"
+/// server_flags = ["--disable", "traefik", "--write-kubeconfig-mode", "0644"]
+/// agent_flags = []
+///
+/// # Install k3s without internet access and share the files with agents.
+/// artifact_dir = "/opt/homesec/k3s"
+/// artifact_port = 43080
+/// ```
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct InstallerConfig {
+    pub distribution: Distribution,
+    /// Extra arguments for servers. Defaults depend on the distribution.
+    pub server_flags: Option<Vec<String>>,
+    /// Extra arguments for agents. Defaults depend on the distribution.
+    pub agent_flags: Option<Vec<String>>,
+    /// File holding the join token for `Distribution::External`.
+    pub token_path: Option<String>,
+    /// File `Distribution::Stub` appends its calls to.
+    pub record_path: Option<String>,
+    /// Local `ArtifactCache` to install k3s from instead of the internet.
+    /// The master serves it to agents on `artifact_port`.
+    pub artifact_dir: Option<String>,
+    pub artifact_port: u16,
+}
+
+impl Default for InstallerConfig {
+    fn default() -> Self {
+        Self {
+            distribution: Distribution::K3s,
+            server_flags: None,
+            agent_flags: None,
+            token_path: None,
+            record_path: None,
+            artifact_dir: None,
+            artifact_port: DEFAULT_ARTIFACT_PORT,
+        }
+    }
+}
+
+/// Builds the installer selected by `config` for the node `node_name`.
+/// Only k3s can install from `artifacts`; the other distributions ignore
+/// them.
+pub fn new_installer(config: &InstallerConfig, node_name: String, labels: BTreeMap<String, String>, artifacts: Option<ArtifactCache>) -> Box<dyn Installer> {
+    match config.distribution {
+        Distribution::K3s => Box::new(K3s {
+            node_name,
+            labels,
+            server_flags: config.server_flags.clone().unwrap_or_else(|| to_strings(K3s::DEFAULT_SERVER_FLAGS)),
+            agent_flags: config.agent_flags.clone().unwrap_or_default(),
+            artifacts,
+        }),
+        Distribution::K0s => Box::new(K0s {
+            node_name,
+            labels,
+            server_flags: config.server_flags.clone().unwrap_or_else(|| to_strings(K0s::DEFAULT_SERVER_FLAGS)),
+            agent_flags: config.agent_flags.clone().unwrap_or_default(),
+        }),
+        Distribution::External => Box::new(External {
+            token_path: config.token_path.clone(),
+        }),
+        Distribution::Stub => Box::new(Stub {
+            node_name,
+            record_path: config.record_path.clone(),
+        }),
+    }
+}
+
+fn to_strings(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+pub(crate) fn run(description: &str, command: &mut Command) -> Result<String> {
+    let output = command.output()?;
+    if !output.status.success() {
+        std::io::stdout().write_all(&output.stdout).unwrap();
+        std::io::stderr().write_all(&output.stderr).unwrap();
+        return Err(anyhow!("{} failed with exit code {}", description, output.status));
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Writes `contents` to `path` readable by root only, including when
+/// the file already exists with looser permissions.
+pub fn write_secret<P: AsRef<Path>>(path: P, contents: &str) -> Result<()> {
+    let mut file = std::fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&path)?;
+    file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+/// First line of a command's output, or `None` if it could not be run.
+fn first_line(command: &mut Command) -> Option<String> {
+    let output = command.output().ok().filter(|output| output.status.success())?;
+    String::from_utf8(output.stdout).ok()?.lines().next().map(|line| line.trim().to_string())
+}
+
+/// Pipes the script at `url` into `sh` with `args`, without putting
+/// anything secret on a command line.
+fn run_remote_script(description: &str, url: &str, args: &[String], env: &[(&str, &str)]) -> Result<()> {
+    println!("running {}", description);
+    run(description, Command::new("sh")
+        .arg("-c")
+        .arg(format!("set -e; curl -sfL {} | sh -s - \"$@\"", url))
+        .arg("sh")
+        .args(args)
+        .envs(env.iter().copied()))?;
+    println!("{} successful", description);
+    Ok(())
+}
+
+pub struct K3s {
+    pub node_name: String,
+    pub labels: BTreeMap<String, String>,
+    pub server_flags: Vec<String>,
+    pub agent_flags: Vec<String>,
+    /// Installs from these instead of downloading k3s.
+    pub artifacts: Option<ArtifactCache>,
+}
+
+impl K3s {
+    pub const DEFAULT_SERVER_FLAGS: &'static [&'static str] = &["--disable", "traefik", "--write-kubeconfig-mode", "0644"];
+    pub const INSTALL_URL: &'static str = "https://get.k3s.io";
+    pub const UNINSTALL_PATH: &'static str = "/usr/local/bin/k3s-uninstall.sh";
+    pub const AGENT_UNINSTALL_PATH: &'static str = "/usr/local/bin/k3s-agent-uninstall.sh";
+    pub const TOKEN_PATH: &'static str = "/var/lib/rancher/k3s/server/node-token";
+    pub const BINARY_PATH: &'static str = "/usr/local/bin/k3s";
+    pub const IMAGES_PATH: &'static str = "/var/lib/rancher/k3s/agent/images";
+
+    fn install(&self, description: &str, args: &[String], env: &[(&str, &str)]) -> Result<()> {
+        let cache = match &self.artifacts {
+            Some(cache) => cache,
+            None => return run_remote_script(description, Self::INSTALL_URL, args, env),
+        };
+        println!("running {} from {}", description, cache.dir.display());
+        std::fs::create_dir_all(Self::IMAGES_PATH)?;
+        for image in cache.images() {
+            std::fs::copy(&image, Path::new(Self::IMAGES_PATH).join(image.file_name().unwrap()))?;
+        }
+        // Renamed into place, since a running k3s keeps the old binary busy.
+        let staged = format!("{}.new", Self::BINARY_PATH);
+        std::fs::copy(cache.path("k3s"), &staged)?;
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o755))?;
+        std::fs::rename(&staged, Self::BINARY_PATH)?;
+        run(description, Command::new("sh")
+            .arg(cache.path("install.sh"))
+            .args(args)
+            .envs(env.iter().copied())
+            .env("INSTALL_K3S_SKIP_DOWNLOAD", "true"))?;
+        println!("{} successful", description);
+        Ok(())
+    }
+
+    fn label_args(&self) -> Vec<String> {
+        self.labels.iter()
+            .flat_map(|(key, value)| vec!["--node-label".to_string(), format!("{}={}", key, value)])
+            .collect()
+    }
+
+    /// Arguments for the install script of a server.
+    pub fn server_args(&self, join: Option<&Join>) -> Vec<String> {
+        let mut args = vec!["server".to_string()];
+        match join {
+            Some(join) => args.extend(vec!["--server".to_string(), join.url()]),
+            None => args.push("--cluster-init".to_string()),
+        }
+        args.extend(self.server_flags.iter().cloned());
+        args.extend(self.label_args());
+        args
+    }
+
+    /// Arguments for the install script of an agent.
+    pub fn agent_args(&self) -> Vec<String> {
+        let mut args = vec!["agent".to_string()];
+        args.extend(self.agent_flags.iter().cloned());
+        args.extend(self.label_args());
+        args
+    }
+
+    /// Stops a k3s agent so the server can be installed in its place. The
+    /// agent's data under /var/lib/rancher is kept, so cached images
+    /// survive the promotion.
+    fn stop_agent(&self) -> Result<()> {
+        if !Path::new(Self::AGENT_UNINSTALL_PATH).exists() {
+            return Ok(());
+        }
+        println!("promoting k3s agent to server");
+        run("stopping k3s agent", Command::new("systemctl").args(["disable", "--now", "k3s-agent"]))?;
+        Ok(())
+    }
+}
+
+impl Installer for K3s {
+    fn install_server(&self, join: Option<&Join>) -> Result<()> {
+        self.stop_agent()?;
+        let mut env = vec![("K3S_NODE_NAME", self.node_name.as_str())];
+        if let Some(join) = join {
+            env.push(("K3S_TOKEN", join.token));
+        }
+        self.install("k3s server install script", &self.server_args(join), &env)
+    }
+
+    fn install_agent(&self, join: &Join) -> Result<()> {
+        let url = join.url();
+        let env = [
+            ("K3S_NODE_NAME", self.node_name.as_str()),
+            ("K3S_URL", url.as_str()),
+            ("K3S_TOKEN", join.token),
+        ];
+        self.install("k3s agent install script", &self.agent_args(), &env)
+    }
+
+    fn uninstall(&self) -> Result<()> {
+        for path in &[Self::UNINSTALL_PATH, Self::AGENT_UNINSTALL_PATH] {
+            if Path::new(path).exists() {
+                run(path, &mut Command::new(path))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn is_installed(&self) -> bool {
+        Path::new(Self::UNINSTALL_PATH).exists() || Path::new(Self::AGENT_UNINSTALL_PATH).exists()
+    }
+
+    fn version(&self) -> Option<String> {
+        first_line(Command::new("k3s").arg("--version"))
+    }
+
+    fn join_token(&self, _server: bool) -> Result<String> {
+        if !Path::new(Self::TOKEN_PATH).exists() {
+            return Err(anyhow!("k3s node token not found at {}", Self::TOKEN_PATH));
+        }
+        Ok(String::from(std::fs::read_to_string(Self::TOKEN_PATH)?.trim()))
+    }
+
+    fn kubectl(&self) -> Command {
+        let mut command = Command::new("k3s");
+        command.arg("kubectl");
+        command
+    }
+}
+
+/// k0s, whose join tokens embed the API address, so `Join::server` is
+/// only informational.
+pub struct K0s {
+    pub node_name: String,
+    pub labels: BTreeMap<String, String>,
+    pub server_flags: Vec<String>,
+    pub agent_flags: Vec<String>,
+}
+
+impl K0s {
+    pub const DEFAULT_SERVER_FLAGS: &'static [&'static str] = &["--enable-worker"];
+    pub const INSTALL_URL: &'static str = "https://get.k0s.sh";
+    pub const TOKEN_PATH: &'static str = "/etc/k0s/join-token";
+    const SERVICES: &'static [&'static str] = &[
+        "/etc/systemd/system/k0scontroller.service",
+        "/etc/systemd/system/k0sworker.service",
+    ];
+
+    fn common_args(&self, join: Option<&Join>) -> Vec<String> {
+        let mut args = vec![format!("--kubelet-extra-args=--hostname-override={}", self.node_name)];
+        if !self.labels.is_empty() {
+            let labels = self.labels.iter()
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect::<Vec<_>>();
+            args.push(format!("--labels={}", labels.join(",")));
+        }
+        if join.is_some() {
+            args.push(format!("--token-file={}", Self::TOKEN_PATH));
+        }
+        args
+    }
+
+    /// Arguments for `k0s` to install a controller.
+    pub fn server_args(&self, join: Option<&Join>) -> Vec<String> {
+        let mut args = to_strings(&["install", "controller"]);
+        args.extend(self.server_flags.iter().cloned());
+        args.extend(self.common_args(join));
+        args
+    }
+
+    /// Arguments for `k0s` to install a worker.
+    pub fn agent_args(&self, join: &Join) -> Vec<String> {
+        let mut args = to_strings(&["install", "worker"]);
+        args.extend(self.agent_flags.iter().cloned());
+        args.extend(self.common_args(Some(join)));
+        args
+    }
+
+    fn install(&self, args: Vec<String>, join: Option<&Join>) -> Result<()> {
+        if !Path::new("/usr/local/bin/k0s").exists() {
+            run_remote_script("k0s download script", Self::INSTALL_URL, &[], &[])?;
+        }
+        if let Some(join) = join {
+            std::fs::create_dir_all(Path::new(Self::TOKEN_PATH).parent().unwrap())?;
+            write_secret(Self::TOKEN_PATH, join.token)?;
+        }
+        println!("running k0s {}", args[..2].join(" "));
+        run("k0s install", Command::new("k0s").args(&args))?;
+        run("k0s start", Command::new("k0s").arg("start"))?;
+        Ok(())
+    }
+}
+
+impl Installer for K0s {
+    fn install_server(&self, join: Option<&Join>) -> Result<()> {
+        if Path::new(Self::SERVICES[1]).exists() {
+            println!("promoting k0s worker to controller");
+            self.uninstall()?;
+        }
+        self.install(self.server_args(join), join)
+    }
+
+    fn install_agent(&self, join: &Join) -> Result<()> {
+        self.install(self.agent_args(join), Some(join))
+    }
+
+    fn uninstall(&self) -> Result<()> {
+        if !self.is_installed() {
+            return Ok(());
+        }
+        run("k0s stop", Command::new("k0s").arg("stop"))?;
+        run("k0s reset", Command::new("k0s").arg("reset"))?;
+        Ok(())
+    }
+
+    fn is_installed(&self) -> bool {
+        Self::SERVICES.iter().any(|path| Path::new(path).exists())
+    }
+
+    fn version(&self) -> Option<String> {
+        first_line(Command::new("k0s").arg("version"))
+    }
+
+    fn join_token(&self, server: bool) -> Result<String> {
+        let role = if server { "--role=controller" } else { "--role=worker" };
+        Ok(run("k0s token create", Command::new("k0s").args(["token", "create", role]))?.trim().to_string())
+    }
+
+    fn kubectl(&self) -> Command {
+        let mut command = Command::new("k0s");
+        command.arg("kubectl");
+        command
+    }
+}
+
+/// A cluster managed outside the daemon. Installing and uninstalling do
+/// nothing, and the join token handed out is read from `token_path`.
+pub struct External {
+    pub token_path: Option<String>,
+}
+
+impl Installer for External {
+    fn install_server(&self, _join: Option<&Join>) -> Result<()> {
+        println!("using externally installed server");
+        Ok(())
+    }
+
+    fn install_agent(&self, _join: &Join) -> Result<()> {
+        println!("using externally installed agent");
+        Ok(())
+    }
+
+    fn uninstall(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn is_installed(&self) -> bool {
+        true
+    }
+
+    fn version(&self) -> Option<String> {
+        None
+    }
+
+    fn join_token(&self, _server: bool) -> Result<String> {
+        match &self.token_path {
+            Some(path) => Ok(std::fs::read_to_string(path)?.trim().to_string()),
+            None => Ok(String::new()),
+        }
+    }
+}
+
+/// A call made on a `Stub`, as recorded in its `record_path`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "call", rename_all = "kebab-case")]
+pub enum StubCall {
+    InstallServer { node: String, server: Option<IpAddr>, token: Option<String> },
+    InstallAgent { node: String, server: IpAddr, token: String },
+    Uninstall { node: String },
+}
+
+/// Installs nothing and appends every install and uninstall to
+/// `record_path`, one JSON `StubCall` per line. Join tokens name the node
+/// that handed them out, so a test can tell whose cluster a node joined.
+pub struct Stub {
+    pub node_name: String,
+    pub record_path: Option<String>,
+}
+
+impl Stub {
+    fn record(&self, call: StubCall) -> Result<()> {
+        println!("stub installer: {:?}", call);
+        if let Some(path) = &self.record_path {
+            let mut line = serde_json::to_vec(&call)?;
+            line.push(b'\n');
+            std::fs::OpenOptions::new().create(true).append(true).open(path)?.write_all(&line)?;
+        }
+        Ok(())
+    }
+}
+
+impl Installer for Stub {
+    fn install_server(&self, join: Option<&Join>) -> Result<()> {
+        self.record(StubCall::InstallServer {
+            node: self.node_name.clone(),
+            server: join.map(|join| join.server),
+            token: join.map(|join| join.token.to_string()),
+        })
+    }
+
+    fn install_agent(&self, join: &Join) -> Result<()> {
+        self.record(StubCall::InstallAgent {
+            node: self.node_name.clone(),
+            server: join.server,
+            token: join.token.to_string(),
+        })
+    }
+
+    fn uninstall(&self) -> Result<()> {
+        self.record(StubCall::Uninstall { node: self.node_name.clone() })
+    }
+
+    /// Whether the last recorded call was an install.
+    fn is_installed(&self) -> bool {
+        match &self.record_path {
+            Some(path) => read_stub_calls(path)
+                .ok()
+                .and_then(|calls| calls.last().cloned())
+                .is_some_and(|call| !matches!(call, StubCall::Uninstall { .. })),
+            None => false,
+        }
+    }
+
+    fn version(&self) -> Option<String> {
+        None
+    }
+
+    fn join_token(&self, server: bool) -> Result<String> {
+        Ok(format!("{}:{}", self.node_name, if server { "server" } else { "agent" }))
+    }
+}
+
+/// Reads the calls a `Stub` recorded at `path`, in order. A missing file
+/// means no calls were made.
+pub fn read_stub_calls<P: AsRef<Path>>(path: P) -> Result<Vec<StubCall>> {
+    let path = path.as_ref();
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    std::fs::read_to_string(path)?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).map_err(|e| anyhow!("{}: invalid stub call: {}", path.display(), e)))
+        .collect()
+}
@@ -0,0 +1,103 @@
+use anyhow::{anyhow, Result};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// An IPv4 address assigned to a local network interface.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Interface {
+    pub name: String,
+    pub addr: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+}
+
+impl Interface {
+    /// Directed broadcast address of the interface's subnet, i.e. its
+    /// address with every host bit set.
+    pub fn broadcast(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.addr) | !u32::from(self.netmask))
+    }
+}
+
+/// Every non-loopback IPv4 address on this host, in the order the kernel
+/// reports them.
+pub fn list_interfaces() -> Result<Vec<Interface>> {
+    Ok(if_addrs::get_if_addrs()?
+        .into_iter()
+        .filter(|iface| !iface.is_loopback())
+        .filter_map(|iface| match iface.addr {
+            if_addrs::IfAddr::V4(v4) => Some(Interface {
+                name: iface.name,
+                addr: v4.ip,
+                netmask: v4.netmask,
+            }),
+            if_addrs::IfAddr::V6(_) => None,
+        })
+        .collect())
+}
+
+/// An interface with an IPv6 link-local address, which is needed to join
+/// a link-local multicast group on it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LinkLocalInterface {
+    pub name: String,
+    pub index: u32,
+}
+
+/// Every interface with an IPv6 link-local address.
+pub fn list_link_local_interfaces() -> Result<Vec<LinkLocalInterface>> {
+    let mut found: Vec<LinkLocalInterface> = Vec::new();
+    for iface in if_addrs::get_if_addrs()? {
+        let index = match (&iface.addr, iface.index) {
+            (if_addrs::IfAddr::V6(v6), Some(index)) if v6.is_link_local() => index,
+            _ => continue,
+        };
+        if !found.iter().any(|f| f.index == index) {
+            found.push(LinkLocalInterface { name: iface.name, index });
+        }
+    }
+    Ok(found)
+}
+
+/// First IPv6 address on `ifaces` that other hosts reach without a scope
+/// id, i.e. one that is neither loopback nor link-local.
+pub fn routable_v6_addr(ifaces: &[LinkLocalInterface]) -> Result<Ipv6Addr> {
+    if_addrs::get_if_addrs()?
+        .into_iter()
+        .filter(|iface| ifaces.iter().any(|selected| Some(selected.index) == iface.index))
+        .find_map(|iface| match iface.addr {
+            if_addrs::IfAddr::V6(v6) if !v6.ip.is_loopback() && !v6.ip.is_unicast_link_local() => Some(v6.ip),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("no routable IPv6 address for the Kubernetes API on the selected interfaces"))
+}
+
+/// Picks the interfaces to broadcast on. With no `names` this is the
+/// first available address; otherwise it is every address on each named
+/// interface, and naming an interface without one is an error.
+pub fn select_interfaces(available: &[Interface], names: &[String]) -> Result<Vec<Interface>> {
+    select(available, names, |iface| &iface.name, "an IPv4 address")
+}
+
+/// Same as `select_interfaces`, for link-local multicast.
+pub fn select_link_local_interfaces(available: &[LinkLocalInterface], names: &[String]) -> Result<Vec<LinkLocalInterface>> {
+    select(available, names, |iface| &iface.name, "an IPv6 link-local address")
+}
+
+fn select<T: Clone>(available: &[T], names: &[String], name_of: fn(&T) -> &String, what: &str) -> Result<Vec<T>> {
+    if names.is_empty() {
+        return available.first()
+            .cloned()
+            .map(|iface| vec![iface])
+            .ok_or_else(|| anyhow!("no network interface with {} is up", what));
+    }
+    let mut selected = Vec::new();
+    for name in names {
+        let matching = available.iter()
+            .filter(|iface| name_of(iface) == name)
+            .collect::<Vec<_>>();
+        if matching.is_empty() {
+            return Err(anyhow!("interface {} does not exist or has no {}", name, what));
+        }
+        selected.extend(matching.into_iter().cloned());
+    }
+    Ok(selected)
+}
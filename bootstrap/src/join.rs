@@ -0,0 +1,88 @@
+//! Joining a cluster whose master is already announcing itself, which a
+//! starting node tries before it calls an election.
+
+use crate::election::{ConnectionDetails, JoinRequest, Message};
+use crate::pump::{every, Inbox};
+use crate::timing::TimingConfig;
+use crate::transport::Transport;
+use anyhow::{anyhow, Result};
+use std::net::{IpAddr, SocketAddr};
+use tokio::time::Instant;
+use uuid::Uuid;
+
+/// A master that acknowledged this node by sending it the join token.
+#[derive(Clone, Debug)]
+pub struct Joined {
+    pub addr: SocketAddr,
+    pub details: ConnectionDetails,
+    pub token: String,
+}
+
+impl Joined {
+    /// Where to reach the master's Kubernetes API: the address it
+    /// announced, or else the one its announcements came from. IPv6
+    /// link-local addresses are refused, since neither a URL nor the
+    /// server's certificate can carry the scope id they need.
+    pub fn api_server(&self) -> Result<IpAddr> {
+        let server = self.details.api_addr.unwrap_or_else(|| self.addr.ip());
+        match server {
+            IpAddr::V6(v6) if v6.is_unicast_link_local() => {
+                Err(anyhow!("master {} announced no routable API address, only {}", self.details.hid, server))
+            }
+            _ => Ok(server),
+        }
+    }
+}
+
+/// Listens `discovery_wait` for the announcement of a master other than
+/// `hid` leading `term` or a later one.
+pub async fn discover_master(inbox: &mut Inbox, hid: Uuid, term: u64, timing: &TimingConfig) -> Result<Option<(SocketAddr, ConnectionDetails)>> {
+    println!("listening for existing master");
+    inbox.enter("discovering");
+    let deadline = Instant::now() + timing.discovery_wait;
+    while let Some((addr, msg)) = inbox.recv_until(deadline).await? {
+        match msg {
+            Message::ConnectionDetails(details) if details.hid != hid && details.term >= term => return Ok(Some((addr, details))),
+            msg => inbox.ignore(&msg),
+        }
+    }
+    Ok(None)
+}
+
+/// Asks the master at `addr` for the join token until it answers with one
+/// encrypted to this node, which is how the master acknowledges it.
+pub async fn request_join(transport: &Transport, inbox: &mut Inbox, hid: Uuid, addr: SocketAddr, master: Uuid, timing: &TimingConfig) -> Result<String> {
+    inbox.enter("requesting join token");
+    let deadline = Instant::now() + timing.join_timeout;
+    let mut retries = every(timing.join_retry);
+    loop {
+        tokio::select! {
+            _ = retries.tick() => {
+                println!("requesting join token from {}", addr);
+                transport.send_to(&Message::JoinRequest(JoinRequest { hid }), addr)?;
+            }
+            received = inbox.recv_until(deadline) => match received? {
+                None => return Err(anyhow!("timed out waiting for join response from master")),
+                Some((_, Message::JoinResponse(response))) if response.hid == hid && response.master == master => {
+                    return transport.auth().open_token(hid, master, &response.nonce, &response.token);
+                }
+                Some((_, msg)) => inbox.ignore(&msg),
+            },
+        }
+    }
+}
+
+/// Joins a master found by `discover_master`. Nothing is broadcast, so
+/// the nodes already in the cluster never see an election; the only
+/// messages sent are join requests to the master. Returns `None` if no
+/// master announced itself.
+pub async fn join_existing_master(transport: &Transport, inbox: &mut Inbox, hid: Uuid, term: u64, timing: &TimingConfig) -> Result<Option<Joined>> {
+    let (addr, details) = match discover_master(inbox, hid, term, timing).await? {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    println!("found master {} in term {}, hid={}", addr, details.term, details.hid);
+    let token = request_join(transport, inbox, hid, addr, details.hid, timing).await?;
+    println!("master {} acknowledged this node", addr);
+    Ok(Some(Joined { addr, details, token }))
+}
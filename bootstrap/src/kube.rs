@@ -0,0 +1,67 @@
+//! Node housekeeping through the Kubernetes API, using the kubectl of the
+//! installed distribution. Only servers have credentials for it, so a
+//! master does this on behalf of agents.
+
+use crate::installer::{run, Installer};
+use anyhow::{anyhow, Result};
+
+/// How long a drain may wait for pods to be evicted.
+pub const DRAIN_TIMEOUT: &str = "120s";
+
+const CONTROL_PLANE_LABELS: &[&str] = &[
+    "node-role.kubernetes.io/control-plane",
+    "node-role.kubernetes.io/master",
+];
+
+/// A Node object in the cluster.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClusterNode {
+    pub name: String,
+    pub control_plane: bool,
+}
+
+/// Parses the output of `kubectl get nodes -o json`.
+pub fn parse_nodes(json: &str) -> Result<Vec<ClusterNode>> {
+    let list: serde_json::Value = serde_json::from_str(json)?;
+    let items = list["items"].as_array().ok_or_else(|| anyhow!("node list has no items"))?;
+    items.iter()
+        .map(|item| {
+            let metadata = &item["metadata"];
+            let name = metadata["name"].as_str().ok_or_else(|| anyhow!("node without a name"))?;
+            let labels = metadata["labels"].as_object();
+            Ok(ClusterNode {
+                name: name.to_string(),
+                control_plane: labels.is_some_and(|labels| CONTROL_PLANE_LABELS.iter().any(|label| labels.contains_key(*label))),
+            })
+        })
+        .collect()
+}
+
+pub fn list_nodes(installer: &dyn Installer) -> Result<Vec<ClusterNode>> {
+    parse_nodes(&run("kubectl get nodes", installer.kubectl().args(["get", "nodes", "-o", "json"]))?)
+}
+
+/// Whether removing `node` would leave other nodes without any control
+/// plane to follow.
+pub fn orphans_agents(nodes: &[ClusterNode], node: &str) -> bool {
+    let others = nodes.iter().filter(|other| other.name != node).collect::<Vec<_>>();
+    !others.is_empty() && !others.iter().any(|other| other.control_plane)
+}
+
+/// Cordons and drains `node`, then deletes its Node object.
+pub fn remove_node(installer: &dyn Installer, node: &str) -> Result<()> {
+    println!("cordoning {}", node);
+    run("kubectl cordon", installer.kubectl().args(["cordon", node]))?;
+    println!("draining {}", node);
+    run("kubectl drain", installer.kubectl().args([
+        "drain",
+        node,
+        "--ignore-daemonsets",
+        "--delete-emptydir-data",
+        "--force",
+        &format!("--timeout={}", DRAIN_TIMEOUT),
+    ]))?;
+    println!("deleting node {}", node);
+    run("kubectl delete node", installer.kubectl().args(["delete", "node", node, "--ignore-not-found"]))?;
+    Ok(())
+}
@@ -1,6 +1,40 @@
-#[macro_use]
-extern crate anyhow;
-
+pub mod artifacts;
+pub mod auth;
+pub mod capture;
+pub mod config;
 pub mod election;
+pub mod hooks;
+pub mod installer;
+pub mod interfaces;
+pub mod join;
+pub mod kube;
+pub mod mdns;
+pub mod multicast;
+pub mod observer;
+pub mod peers;
+pub mod pump;
+pub mod replay;
+pub mod state;
+pub mod timing;
+pub mod transport;
+pub mod wire;
+pub use artifacts::*;
+pub use auth::*;
+pub use capture::*;
+pub use config::*;
 pub use election::*;
-
+pub use hooks::*;
+pub use installer::*;
+pub use interfaces::*;
+pub use join::*;
+pub use kube::*;
+pub use mdns::*;
+pub use multicast::*;
+pub use observer::*;
+pub use peers::*;
+pub use pump::*;
+pub use replay::*;
+pub use state::*;
+pub use timing::*;
+pub use transport::*;
+pub use wire::*;
@@ -1,16 +1,17 @@
 use clap::{Parser, Subcommand};
 use anyhow::{anyhow, Result, Error};
 use std::process::Command;
-use std::net::{SocketAddr, UdpSocket};
+use serde::Serialize;
+use std::net::{IpAddr, SocketAddr, TcpListener, UdpSocket};
 use std::time::{Duration, SystemTime};
 use uuid::Uuid;
 use std::io::{self, Write};
+use std::collections::{BTreeMap, HashSet};
 use std::path::Path;
-use std::path::PathBuf;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
 
-mod election;
-
-use election::*;
+use homesec_bootstrap::*;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -21,49 +22,140 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Commands {
-    Daemon,
-    Remove,
+    Daemon {
+        /// Interface to broadcast on. May be repeated, and overrides the
+        /// interfaces in the config file.
+        #[arg(long = "interface")]
+        interfaces: Vec<String>,
+    },
+    /// Leaves the cluster and uninstalls this node.
+    Remove {
+        /// Remove even if the cluster cannot be left cleanly, or if this
+        /// is the only server.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Prints this node's identity, role and install state, and the peers
+    /// heard during a short passive listen.
+    Status {
+        #[arg(long)]
+        json: bool,
+        /// Seconds to listen for peers.
+        #[arg(long, default_value_t = 3)]
+        listen: u64,
+    },
+    /// Shows a live table of every node heard on the bootstrap port.
+    Peers {
+        /// Appends every message heard to this capture file.
+        #[arg(long)]
+        record: Option<String>,
+    },
+    /// Replays captures through a fresh election and prints every vote
+    /// and result it decides on.
+    Replay {
+        /// Capture files, oldest first.
+        #[arg(required = true)]
+        captures: Vec<String>,
+    },
 }
 
-const BUFFER_SIZE: usize = 8192;
+const DEFAULT_PORT: i32 = 43000;
+
+fn read_port() -> Result<i32> {
+    match std::env::var("PORT") {
+        Ok(port) => Ok(port.parse::<i32>()?),
+        Err(_) => Ok(DEFAULT_PORT),
+    }
+}
 
 fn get_port() -> Result<i32> {
-    if let Ok(port) = std::env::var("PORT") {
-        let port = port.parse::<i32>()?;
+    let port = read_port()?;
+    if std::env::var("PORT").is_ok() {
         println!("PORT environment variable set to {}", port);
-        Ok(port)
     } else {
-        let default_port = 43000;
-        println!("defaulting to port {}", default_port);
-        Ok(default_port)
+        println!("defaulting to port {}", port);
     }
+    Ok(port)
 }
 
-fn get_broadcast_address(port: i32) -> Result<String> {
+/// Directed broadcast addresses for the interfaces named by `--interface`
+/// or the config, or for the first interface if neither names any.
+/// `BROADCAST_ADDR` overrides them with a comma-separated list, which can
+/// also name each peer directly, as the loopback tests do.
+fn get_broadcast_addrs(port: i32, interfaces: &[String]) -> Result<Vec<SocketAddr>> {
     if let Ok(broadcast_addr) = std::env::var("BROADCAST_ADDR") {
-        return Ok(broadcast_addr);
+        println!("BROADCAST_ADDR environment variable set to {}", broadcast_addr);
+        return broadcast_addr.split(',').map(|addr| Ok(addr.trim().parse()?)).collect();
     }
-    let output = Command::new("hostname")
-        .args(&["-I"])
-        .output()
-        .expect("failed to probe ip address");
-    if !output.status.success() {
-        std::io::stdout().write_all(&output.stdout).unwrap();
-        std::io::stderr().write_all(&output.stderr).unwrap();
-        return Err(anyhow!("hostname failed with exit code {}", output.status));
+    let selected = select_interfaces(&list_interfaces()?, interfaces)?;
+    let mut addrs = Vec::new();
+    for iface in selected {
+        let addr = SocketAddr::new(iface.broadcast().into(), port as u16);
+        println!("broadcasting on {} ({}/{}) via {}", iface.name, iface.addr, iface.netmask, addr);
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+    Ok(addrs)
+}
+
+fn get_key_path() -> String {
+    std::env::var("CLUSTER_KEY_PATH").unwrap_or_else(|_| String::from(KEY_PATH))
+}
+
+fn get_config_path() -> String {
+    std::env::var("BOOTSTRAP_CONFIG").unwrap_or_else(|_| String::from(CONFIG_PATH))
+}
+
+/// Reads the config, with the server count and timings overridden by
+/// the environment.
+fn load_config() -> Result<BootstrapConfig> {
+    let mut config = BootstrapConfig::load(get_config_path())?;
+    if let Ok(count) = std::env::var("SERVER_COUNT") {
+        config.server_count = count.parse().map_err(|e| anyhow!("SERVER_COUNT: {}", e))?;
+        println!("SERVER_COUNT environment variable set to {}", count);
+    }
+    config.timing.apply_env(|name| std::env::var(name).ok())?;
+    config.validate()?;
+    Ok(config)
+}
+
+fn get_config() -> Result<BootstrapConfig> {
+    let path = get_config_path();
+    if !Path::new(&path).exists() {
+        println!("no config at {}, using defaults", path);
+    }
+    let config = load_config()?;
+    println!("priority={}, master_eligible={}, labels={:?}", config.priority, config.master_eligible, config.labels);
+    if config.server_count % 2 == 0 {
+        println!("warning: an even server_count gives etcd no extra fault tolerance");
+    }
+    if config.timing != TimingConfig::default() {
+        println!("timing: {:?}", config.timing);
+    }
+    Ok(config)
+}
+
+const HID_PATH: &str = "/etc/hid";
+
+fn get_hid_path() -> String {
+    std::env::var("BOOTSTRAP_HID_PATH").unwrap_or_else(|_| String::from(HID_PATH))
+}
+
+fn read_hid() -> Result<Option<Uuid>> {
+    let path = get_hid_path();
+    if Path::new(&path).exists() {
+        Ok(Some(std::fs::read_to_string(&path)?.trim().parse()?))
+    } else {
+        Ok(None)
     }
-    let broadcast_ip = String::from_utf8(output.stdout)
-        .expect("failed to parse hostname output");
-    let broadcast_ip: String = format!("{}.255",
-        broadcast_ip.chars().take(broadcast_ip.rfind(".").unwrap()).collect::<String>());
-    Ok(format!("{}:{}", broadcast_ip, port))
 }
 
 fn get_hid() -> Result<Uuid> {
-    let path = "/etc/hid";
-    if std::path::Path::new(path).exists() {
+    let path = get_hid_path();
+    if let Some(hid) = read_hid()? {
 	println!("found existing hid at {}", path);
-        Ok(std::fs::read_to_string(path)?.trim().parse()?)
+        Ok(hid)
     } else {
         let hid = Uuid::new_v4();
         let s = hid.to_hyphenated().to_string();
@@ -73,309 +165,984 @@ fn get_hid() -> Result<Uuid> {
     }
 }
 
-fn elect_master(socket: &mut UdpSocket, broadcast_addr: &str, hid: Uuid, is_master: bool, buf: &mut [u8]) -> Result<(SocketAddr, Uuid)> {
-    println!("electing master");
+/// The master a node settled on.
+struct Elected {
+    addr: SocketAddr,
+    hid: Uuid,
+    term: u64,
+    /// Every candidate seen, from most to least preferred.
+    ranking: Vec<Uuid>,
+    /// Candidates that already run the control plane.
+    servers: Vec<Uuid>,
+}
+
+/// Runs an election starting in `term`. Votes and results are checked as
+/// soon as a message could change them, and again every
+/// `election_interval` when this node also announces itself.
+async fn elect_master(transport: &Transport, inbox: &mut Inbox, hid: Uuid, is_master: bool, term: u64, config: &BootstrapConfig, state: &mut StateFile) -> Result<Elected> {
+    println!("electing master in term {}", term);
+    state.set_phase(Phase::Electing)?;
+    inbox.enter("electing");
     let mut d = Election::new();
-    let delay = Duration::from_millis(1000);
+    d.term = term;
+    d.delay = config.timing.election_delay;
+    d.node_timeout = config.timing.node_timeout;
+    d.cluster_size = config.cluster_size;
+    let mut appearances = every(config.timing.election_interval);
     loop {
-        match socket.recv_from(buf) {
-            Ok((n, addr)) => {
-                let msg: Message = bincode::deserialize(&buf[..n])?;
-                d.process_message(addr, &msg)?;
+        tokio::select! {
+            received = inbox.recv() => {
+                let (addr, msg) = received?;
+                if let Message::JoinRequest(_) | Message::JoinResponse(_) = msg {
+                    inbox.ignore(&msg);
+                    continue;
+                }
+                if let Err(e) = d.process_message(addr, &msg) {
+                    println!("ignoring message from {}: {}", addr, e);
+                }
+                // Appearances only change the outcome once the delay has
+                // passed, which the next tick catches.
+                if let Message::Appearance(_) = msg {
+                    continue;
+                }
             }
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
-            Err(e) => return Err(Error::from(e)),
-        } 
-        match d.check_result() {
-            (Some((addr, hid)), false) => {
-                let msg = Message::ElectionResult(ElectionResult {
-                    addr,
+            _ = appearances.tick() => {
+                println!("broadcasting appearance message over {:?}", transport.broadcast_addrs());
+                transport.broadcast(&Message::Appearance(AppearanceMessage {
+                    priority: d.priority,
                     hid,
-                });
-                let encoded: Vec<u8> = bincode::serialize(&msg)?;
-                socket.send_to(&encoded[..], &broadcast_addr)?;
-                println!("master elected: {}, {}", addr, hid);
-                return Ok((addr, hid));
-            }
-            (None, true) => {
-                println!("broadcasting reset message to {}", broadcast_addr);
-                let encoded: Vec<u8> = bincode::serialize(&Message::Reset)?;
-                socket.send_to(&encoded[..], &broadcast_addr)?;
+                    is_master,
+                    static_priority: config.priority,
+                    master_eligible: config.master_eligible,
+                    labels: config.labels.clone(),
+                    term: d.term,
+                    role: state.state().role,
+                }))?;
+                // Repeated in case it was lost.
+                if let Some((addr, hid)) = d.voted_for {
+                    transport.broadcast(&Message::CastVote(CastVote { addr, hid, term: d.term }))?;
+                }
             }
-            (None, false) => {}
-            _ => unreachable!(),
+        }
+        let result = d.check_result();
+        state.set_term(d.term)?;
+        if let Some((addr, hid)) = result {
+            transport.broadcast(&Message::ElectionResult(ElectionResult {
+                addr,
+                hid,
+                term: d.term,
+            }))?;
+            println!("master elected in term {}: {}, {}", d.term, addr, hid);
+            let ranking = d.ranking().into_iter().map(|(_, hid)| hid).collect();
+            return Ok(Elected { addr, hid, term: d.term, ranking, servers: d.servers() });
         }
         if let Some((addr, hid)) = d.check_vote() {
-            let msg = Message::CastVote(CastVote {
+            transport.broadcast(&Message::CastVote(CastVote {
                 addr,
                 hid,
-            });
-            let encoded: Vec<u8> = bincode::serialize(&msg)?;
-            socket.send_to(&encoded[..], &broadcast_addr)?;
-        }
-        // Send an appearance message
-        println!("broadcasting appearance message over {}", &broadcast_addr);
-        let msg = Message::Appearance(AppearanceMessage {
-            priority: d.priority,
-            hid,
-            is_master,
-        });
-        let encoded: Vec<u8> = bincode::serialize(&msg)?;
-        socket.send_to(&encoded[..], &broadcast_addr)?;
-        std::thread::sleep(delay);
+                term: d.term,
+            }))?;
+        }
     }
 }
 
-fn listen_for_existing_master(socket: &mut UdpSocket, wait_period: Duration, buf: &mut [u8]) -> Result<Option<(SocketAddr, Uuid)>> {
-    println!("listening for existing master");
-    let start = SystemTime::now();
-    let delay = Duration::from_millis(100);
-    loop {
-        let elapsed = SystemTime::now().duration_since(start).unwrap();
-        if elapsed > wait_period {
-            return Ok(None);
-        }
-        match socket.recv_from(buf) {
-            Ok((n, addr)) => {
-                let msg: Message = bincode::deserialize(&buf[..n])?;
-                match msg {
-                    Message::Appearance(msg) if msg.is_master => {
-                        return Ok(Some((addr, msg.hid)));
-                    }
-                    _ => {}
-                }
-            }
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
-            Err(e) => return Err(Error::from(e)),
+/// Listens for `ConnectionDetails` from a master other than `hid` in
+/// `term` or later, which means this node was replaced while it was down.
+async fn listen_for_other_master(inbox: &mut Inbox, hid: Uuid, term: u64, timing: &TimingConfig) -> Result<Option<(SocketAddr, ConnectionDetails)>> {
+    println!("checking for another master");
+    inbox.enter("checking for another master");
+    let deadline = Instant::now() + timing.master_timeout;
+    while let Some((addr, msg)) = inbox.recv_until(deadline).await? {
+        match msg {
+            Message::ConnectionDetails(details) if details.hid != hid && details.term >= term => return Ok(Some((addr, details))),
+            msg => inbox.ignore(&msg),
         }
-        std::thread::sleep(delay);
     }
+    Ok(None)
+}
+
+/// Runs slow, synchronous work such as an install without holding up
+/// the receive task.
+fn blocking<T>(f: impl FnOnce() -> T) -> T {
+    tokio::task::block_in_place(f)
 }
 
-fn get_node_token() -> Result<String> {
-    let path = "/var/lib/rancher/k3s/server/node-token";
-    if !Path::new(path).exists() {
-        return Err(anyhow!("k3s node token not found at {}", path));
+/// Runs hooks one at a time on a thread of their own, so that a slow
+/// hook neither holds up the daemon nor overtakes an earlier event.
+fn spawn_hooks(hooks: ExecHooks) -> mpsc::UnboundedSender<HookEvent> {
+    let (tx, mut rx) = mpsc::unbounded_channel::<HookEvent>();
+    std::thread::spawn(move || {
+        while let Some(event) = rx.blocking_recv() {
+            fire_hook(&hooks, &event);
+        }
+    });
+    tx
+}
+
+/// Picks the control plane: the master followed by the servers it chose
+/// before, which already hold etcd state, and then the next best ranked
+/// candidates, up to `count` servers in total.
+fn choose_servers(master: Uuid, previous: &[Uuid], ranking: &[Uuid], count: usize) -> Vec<Uuid> {
+    let mut servers = vec![master];
+    for hid in previous.iter().chain(ranking) {
+        if servers.len() < count && !servers.contains(hid) {
+            servers.push(*hid);
+        }
     }
-    println!("k3s node token found at {}", path);
-    Ok(String::from(std::fs::read_to_string(path)?.trim()))
+    servers
 }
 
-fn run_master(hid: Uuid, socket: &mut UdpSocket, broadcast_addr: &str, buf: &mut [u8]) -> Result<()> {
-    println!("running k3s master install script");
-    let output = Command::new("sh")
-        .args(&[
-            "-c",
-            &format!("set -e; curl -sfL https://get.k3s.io | INSTALL_K3S_EXEC=\"server --disable traefik --write-kubeconfig-mode 0644 --kube-apiserver-arg enable-admission-plugins=PodSecurityPolicy,NodeRestriction\" K3S_NODE_NAME=pi-{} sh -s -", hid),
-        ])
-        .output()
-        .expect("build failed");
-    if !output.status.success() {
-        std::io::stdout().write_all(&output.stdout).unwrap();
-        std::io::stderr().write_all(&output.stderr).unwrap();
-        return Err(anyhow!("k3s master install failed with exit code {}", output.status));
+fn node_name(hid: Uuid) -> String {
+    format!("pi-{}", hid)
+}
+
+/// Starts serving the artifact cache to agents, returning where it is
+/// served.
+fn serve_artifact_cache(cache: ArtifactCache, port: u16) -> Result<ArtifactSource> {
+    println!("serving artifacts from {} on port {}", cache.dir.display(), port);
+    let source = cache.source(port);
+    serve_artifacts(cache, TcpListener::bind(("0.0.0.0", port))?);
+    Ok(source)
+}
+
+/// Serves as master, announcing `details` and so renewing the lease for
+/// its term. Returns the later term that other nodes moved on to, in
+/// which this node stands for re-election.
+async fn run_master(details: ConnectionDetails, transport: &Transport, inbox: &mut Inbox, config: &BootstrapConfig, artifacts: Option<ArtifactCache>, state: &mut StateFile, hooks: &mpsc::UnboundedSender<HookEvent>) -> Result<u64> {
+    let (hid, term) = (details.hid, details.term);
+    let installer = new_installer(&config.installer, node_name(hid), config.labels.clone(), artifacts.clone());
+    if state.state().has_joined(Role::Master, hid) && installer.is_installed() {
+        println!("master install already completed, skipping");
+    } else if state.state().role == Some(Role::Server) {
+        // Already an etcd member, so the control plane survives the loss
+        // of the old master and only the announcements move here.
+        println!("taking over as master from existing server");
+    } else {
+        state.begin_install(Role::Master)?;
+        blocking(|| installer.install_server(None))?;
     }
-    let token = get_node_token()?;
-    println!("k3s install script successful, token={}", &token);
+    // Reported once this node hears its first announcement back, which
+    // shows the address peers reach it at, and not again when it goes on
+    // to lead a later term.
+    let mut elected = (!state.state().has_joined(Role::Master, hid)).then_some(HookEvent {
+        event: HookKind::ElectedMaster,
+        hid,
+        role: Some(Role::Master),
+        master_hid: Some(hid),
+        master_addr: None,
+        term,
+    });
+    state.joined(Role::Master, hid, None, installer.version())?;
+    state.set_term(term)?;
+    state.set_servers(&details.servers)?;
+    let server_token = blocking(|| installer.join_token(true))?;
+    let agent_token = blocking(|| installer.join_token(false))?;
+    let _advertiser = if config.mdns {
+        println!("advertising {} over mdns", SERVICE_TYPE);
+        Some(Advertiser::start(hid, Role::Master.as_str(), transport.local_addr()?.port())?)
+    } else {
+        None
+    };
+    println!("announcing servers {:?} in term {}", details.servers, term);
+    inbox.enter("serving as master");
+    let mut announcements = every(config.timing.announce_interval);
+    // Nodes being drained in the background, and those already removed.
+    let (drained_tx, mut drained) = mpsc::unbounded_channel::<(Uuid, Result<()>)>();
+    let mut draining = HashSet::new();
+    let mut left = HashSet::new();
     loop {
-        let msg = Message::ConnectionDetails(ConnectionDetails {
-            hid,
-            token: token.clone(),
-        });
-        let encoded: Vec<u8> = bincode::serialize(&msg)?;
-        socket.send_to(&encoded[..], &broadcast_addr)?;
-        std::thread::sleep(Duration::from_millis(100));
+        tokio::select! {
+            _ = announcements.tick() => {
+                transport.broadcast(&Message::ConnectionDetails(details.clone()))?;
+            }
+            Some((node, result)) = drained.recv() => {
+                draining.remove(&node);
+                match result {
+                    Ok(()) => {
+                        println!("removed {} from the cluster", node);
+                        left.insert(node);
+                        transport.broadcast(&Message::Leave(LeaveMessage { hid: node }))?;
+                    }
+                    Err(e) => println!("failed to remove {} from the cluster: {}", node, e),
+                }
+            }
+            received = inbox.recv() => match received? {
+                (addr, Message::ConnectionDetails(own)) if own.hid == hid => {
+                    if let Some(mut event) = elected.take() {
+                        event.master_addr = Some(addr);
+                        let _ = hooks.send(event);
+                    }
+                }
+                (_, Message::Leave(LeaveMessage { hid: node })) if node == hid => {}
+                (addr, Message::Leave(LeaveMessage { hid: node })) => {
+                    if left.contains(&node) {
+                        // The node missed the confirmation, so repeat it.
+                        transport.broadcast(&Message::Leave(LeaveMessage { hid: node }))?;
+                    } else if draining.insert(node) {
+                        println!("{} is leaving, hid={}; draining it", addr, node);
+                        let installer_config = config.installer.clone();
+                        let drained_tx = drained_tx.clone();
+                        tokio::task::spawn_blocking(move || {
+                            let installer = new_installer(&installer_config, node_name(hid), BTreeMap::new(), None);
+                            let _ = drained_tx.send((node, remove_node(installer.as_ref(), &node_name(node))));
+                        });
+                    }
+                }
+                (addr, Message::JoinRequest(JoinRequest { hid: agent })) => {
+                    // Agents that found this node over mdns may not hear the
+                    // broadcast announcements, so answer them directly too.
+                    transport.send_to(&Message::ConnectionDetails(details.clone()), addr)?;
+                    println!("acknowledging {} with a join token, hid={}", addr, agent);
+                    let token = if details.servers.contains(&agent) { &server_token } else { &agent_token };
+                    let (nonce, token) = transport.auth().seal_token(agent, hid, token)?;
+                    transport.send_to(&Message::JoinResponse(JoinResponse {
+                        hid: agent,
+                        master: hid,
+                        nonce,
+                        token,
+                    }), addr)?;
+                }
+                (addr, msg) if msg.term().is_some_and(|later| later > term) => {
+                    let later = msg.term().unwrap_or_default();
+                    println!("{} moved on to term {}; standing down from term {}", addr, later, term);
+                    return Ok(later);
+                }
+                (addr, Message::ConnectionDetails(other)) if other.hid != hid && other.term == term => {
+                    println!("{} also holds a lease for term {}, hid={}", addr, term, other.hid);
+                    return Ok(term + 1);
+                }
+                (_, msg) => inbox.ignore(&msg),
+            },
+        }
     }
-    Ok(())
 }
 
-fn wait_for_next_election(socket: &mut UdpSocket, buf: &mut [u8]) -> Result<()> {
+/// Why an agent stopped following its master.
+enum AgentExit {
+    /// Another node moved on to this later term.
+    NewTerm(u64),
+    /// The master's lease for this term lapsed, or the master left.
+    MasterLost(u64),
+}
+
+/// Follows the master's `ConnectionDetails` heartbeat, which renews its
+/// lease for `term`, until it stops for longer than `master_timeout` or
+/// another node moves on to a later term.
+async fn watch_master(inbox: &mut Inbox, master: Uuid, mut term: u64, timing: &TimingConfig) -> Result<AgentExit> {
+    inbox.enter("following master");
+    let mut deadline = Instant::now() + timing.master_timeout;
     loop {
-        match socket.recv_from(buf) {
-            Ok((n, addr)) => {
-                let msg: Message = bincode::deserialize(&buf[..n])?;
-                match msg {
-                    Message::Reset => {
-                        return Ok(());
-                    }
-                    _ => {}
-                }
+        match inbox.recv_until(deadline).await? {
+            None => {
+                println!("no heartbeat from master {} in {:?}", master, timing.master_timeout);
+                return Ok(AgentExit::MasterLost(term));
             }
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
-            Err(e) => return Err(Error::from(e)),
+            Some((addr, Message::Reset)) => {
+                println!("{} asked for a new election", addr);
+                return Ok(AgentExit::NewTerm(term + 1));
+            }
+            Some((_, Message::Leave(LeaveMessage { hid }))) if hid == master => {
+                println!("master {} left the cluster", master);
+                return Ok(AgentExit::MasterLost(term));
+            }
+            Some((_, Message::ConnectionDetails(details))) if details.hid == master && details.term >= term => {
+                term = details.term;
+                deadline = Instant::now() + timing.master_timeout;
+            }
+            Some((addr, msg)) if msg.term().is_some_and(|later| later > term) => {
+                let later = msg.term().unwrap_or_default();
+                println!("{} moved on to term {}", addr, later);
+                return Ok(AgentExit::NewTerm(later));
+            }
+            Some((_, msg)) => inbox.ignore(&msg),
         }
-        std::thread::sleep(Duration::from_millis(1000));
     }
 }
 
-fn wait_for_connection_details(socket: &mut UdpSocket, buf: &mut [u8]) -> Result<(SocketAddr, ConnectionDetails)> {
-    let start = SystemTime::now();
-    let timeout = Duration::from_secs(150);
+/// Waits for the announcement of a master elected in `term` or later. If
+/// none arrives within `mdns_fallback_delay`, also browses mdns for the
+/// master and asks it directly.
+async fn wait_for_connection_details(transport: &Transport, inbox: &mut Inbox, hid: Uuid, term: u64, config: &BootstrapConfig) -> Result<(SocketAddr, ConnectionDetails)> {
+    let timing = &config.timing;
+    inbox.enter("waiting for connection details");
+    let start = Instant::now();
+    let deadline = start + timing.join_timeout;
+    let mut retries = every(timing.join_retry);
+    let mut browser = None;
+    let mut found = None;
     loop {
-        let elapsed = SystemTime::now().duration_since(start).unwrap();
-        if elapsed > timeout {
-            return Err(anyhow!("timed out waiting for connection details from master"));
-        }
-        match socket.recv_from(buf) {
-            Ok((n, addr)) => {
-                let msg: Message = bincode::deserialize(&buf[..n])?;
-                match msg {
-                    Message::ConnectionDetails(details) => {
-                        return Ok((addr, details));
-                    }
-                    _ => {}
+        tokio::select! {
+            received = inbox.recv_until(deadline) => match received? {
+                None => return Err(anyhow!("timed out waiting for connection details from master")),
+                Some((addr, Message::ConnectionDetails(details))) if details.term >= term => return Ok((addr, details)),
+                Some((_, msg)) => inbox.ignore(&msg),
+            },
+            _ = retries.tick() => {
+                let elapsed = start.elapsed();
+                if config.mdns && browser.is_none() && elapsed > timing.mdns_fallback_delay {
+                    println!("no connection details after {:?}, browsing mdns for {}", elapsed, SERVICE_TYPE);
+                    browser = Some(Browser::start()?);
+                }
+                if let Some(record) = browser.as_ref().and_then(|browser| browser.poll()) {
+                    println!("found master over mdns, hid={}, addrs={:?}", record.hid, record.addrs);
+                    found = record.bootstrap_addr();
+                }
+                if let Some(addr) = found {
+                    transport.send_to(&Message::JoinRequest(JoinRequest { hid }), addr)?;
                 }
             }
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
-            Err(e) => return Err(Error::from(e)),
         }
-        std::thread::sleep(Duration::from_millis(1000));
     }
 }
 
+/// Joins the master elected in `term` once it announces itself.
+async fn join_elected_master(transport: &Transport, inbox: &mut Inbox, hid: Uuid, term: u64, config: &BootstrapConfig) -> Result<Joined> {
+    let (addr, details) = wait_for_connection_details(transport, inbox, hid, term, config).await?;
+    println!("received connection details, addr={}, hid={}, term={}", addr, details.hid, details.term);
+    let token = request_join(transport, inbox, hid, addr, details.hid, &config.timing).await?;
+    println!("received join token from {}", addr);
+    Ok(Joined { addr, details, token })
+}
 
-fn run_agent(hid: Uuid, socket: &mut UdpSocket, buf: &mut [u8]) -> Result<()> {
-    let (addr, details) = wait_for_connection_details(socket, buf)?;
-    println!("received connection details, addr={}, token={}", addr, &details.token);
-    let addr: String = match addr {
-        SocketAddr::V4(addr) => addr.ip().to_string(),
-        SocketAddr::V6(addr) => addr.ip().to_string(),
+/// Joins the cluster of the master that acknowledged this node, as an
+/// additional server if the master chose this node for the control plane
+/// and as an agent otherwise. A node that already joined as a server stays
+/// one, and a node that already joined this master skips the install.
+async fn run_agent(hid: Uuid, joined: Joined, inbox: &mut Inbox, config: &BootstrapConfig, artifacts: Option<&ArtifactCache>, state: &mut StateFile, hooks: &mpsc::UnboundedSender<HookEvent>) -> Result<AgentExit> {
+    let server = joined.api_server()?;
+    let Joined { addr, details, token } = joined;
+    let role = if state.state().role == Some(Role::Server) || details.servers.contains(&hid) {
+        Role::Server
+    } else {
+        Role::Agent
     };
-    println!("running k3s agent install script");
-    let output = Command::new("sh")
-        .args(&[
-            "-c",
-            &format!("set -e; curl -sfL https://get.k3s.io | K3S_URL=https://{}:6443 K3S_TOKEN={} K3S_NODE_NAME=pi-{} sh -s -", &addr, &details.token, hid),
-        ])
-        .output()
-        .expect("build failed");
-    if !output.status.success() {
-        std::io::stdout().write_all(&output.stdout).unwrap();
-        std::io::stderr().write_all(&output.stderr).unwrap();
-        return Err(anyhow!("k3s master install failed with exit code {}", output.status));
+    let installer = new_installer(&config.installer, node_name(hid), config.labels.clone(), None);
+    if state.state().has_joined(role, details.hid) && installer.is_installed() {
+        println!("already joined master {} as {}, skipping install", details.hid, role.as_str());
+    } else {
+        let artifacts = blocking(|| agent_artifacts(addr, &details, config, artifacts))?;
+        let installer = new_installer(&config.installer, node_name(hid), config.labels.clone(), artifacts);
+        let join = Join {
+            server,
+            token: &token,
+        };
+        state.begin_install(role)?;
+        blocking(|| match role {
+            Role::Server => installer.install_server(Some(&join)),
+            _ => installer.install_agent(&join),
+        })?;
     }
-    println!("k3s agent install script successful");
-    Ok(wait_for_next_election(socket, buf)?)
+    state.joined(role, details.hid, Some(addr), installer.version())?;
+    state.set_term(details.term)?;
+    let event = |event, term| HookEvent {
+        event,
+        hid,
+        role: Some(role),
+        master_hid: Some(details.hid),
+        master_addr: Some(addr),
+        term,
+    };
+    let joined = match role {
+        Role::Server => HookKind::JoinedAsServer,
+        _ => HookKind::JoinedAsAgent,
+    };
+    let _ = hooks.send(event(joined, details.term));
+    let exit = watch_master(inbox, details.hid, details.term, &config.timing).await?;
+    if let AgentExit::MasterLost(term) = exit {
+        let _ = hooks.send(event(HookKind::MasterLost, term));
+    }
+    Ok(exit)
 }
 
-const MASTER_PATH: &'static str = "/etc/k3s-master";
+/// The artifacts to install from: the local cache if there is one, or
+/// else the master's, fetched and checked against its announced digest.
+fn agent_artifacts(addr: SocketAddr, details: &ConnectionDetails, config: &BootstrapConfig, artifacts: Option<&ArtifactCache>) -> Result<Option<ArtifactCache>> {
+    Ok(match (artifacts, details.artifacts) {
+        (Some(cache), _) => Some(cache.clone()),
+        (None, Some(source)) if config.installer.distribution == Distribution::K3s => {
+            let server = SocketAddr::new(addr.ip(), source.port);
+            println!("fetching artifacts from {} into {}", server, FETCHED_ARTIFACTS_PATH);
+            Some(ArtifactCache::fetch(server, source.sums, FETCHED_ARTIFACTS_PATH)?)
+        }
+        _ => None,
+    })
+}
+
+/// Role file written by earlier versions.
+const ROLE_PATH: &str = "/etc/k3s-role";
+
+/// Marker written by earlier versions on the master only.
+const MASTER_PATH: &str = "/etc/k3s-master";
 
-fn get_master_status() -> Result<bool> {
-    Ok(Path::new(MASTER_PATH).exists())
+fn get_state_path() -> String {
+    std::env::var("BOOTSTRAP_STATE_PATH").unwrap_or_else(|_| String::from(STATE_PATH))
 }
 
-fn set_master_status(value: bool) -> Result<()> {
-    if value {
-        if !get_master_status()? {
-            //if let Err(err) = Command::new("/usr/local/bin/k3s-uninstall.sh").output() {
-            //    if err.raw_os_error().unwrap_or(2) != 2 {
-            //        // exit code 2 is NotFound
-            //        return Err(Error::from(err));
-            //    }
-            //}
-            std::fs::write(MASTER_PATH, "")?;
+fn get_legacy_role() -> Result<Option<Role>> {
+    if Path::new(ROLE_PATH).exists() {
+        return Ok(Some(Role::parse(std::fs::read_to_string(ROLE_PATH)?.trim())?));
+    }
+    if Path::new(MASTER_PATH).exists() {
+        return Ok(Some(Role::Master));
+    }
+    Ok(None)
+}
+
+/// Opens the state file, carrying over the role recorded by earlier
+/// versions if there is none yet.
+fn open_state(hid: Uuid) -> Result<StateFile> {
+    let path = get_state_path();
+    let mut state = StateFile::open(&path)?;
+    if !state.exists() {
+        if let Some(role) = get_legacy_role()? {
+            println!("migrating role {} to {}", role.as_str(), path);
+            state.update(|state| {
+                state.phase = Phase::joined(role);
+                state.role = Some(role);
+                if role == Role::Master {
+                    state.master_hid = Some(hid);
+                }
+            })?;
         }
-    } else if get_master_status()? {
-        //if let Err(err) = Command::new("/usr/local/bin/k3s-agent-uninstall.sh").output() {
-        //    if err.raw_os_error().unwrap_or(2) != 2 {
-        //        return Err(Error::from(err));
-        //    }
-        //}
-        std::fs::remove_file(MASTER_PATH)?;
     }
-    Ok(())
+    remove_file_if_exists(ROLE_PATH)?;
+    remove_file_if_exists(MASTER_PATH)?;
+    Ok(state)
+}
+
+fn remove_file_if_exists(path: &str) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(Error::from(e)),
+    }
 }
 
-fn daemon_main() -> Result<()> {
+async fn daemon_main(interfaces: Vec<String>) -> Result<()> {
     println!("starting daemon");
     let hid = get_hid()?;
-    let mut is_master = get_master_status()?;
-    println!("hid={}", hid);
-    let port = get_port()?;
-    let broadcast_addr = get_broadcast_address(port)?;
-    println!("using broadcast address {}", broadcast_addr);
-    let mut socket = UdpSocket::bind(format!("0.0.0.0:{}", port))?;
-    socket.set_nonblocking(true)?;
-    socket.set_broadcast(true)?;
-    let mut buf = [0; BUFFER_SIZE];
-    if !is_master {
-        println!("finding master");
-        let wait_period = Duration::from_secs(5);
-        let (master_addr, master_hid) = listen_for_existing_master(&mut socket, wait_period, &mut buf[..])?
-            .unwrap_or(elect_master(&mut socket, &broadcast_addr, hid, is_master, &mut buf[..])?);
-        is_master = master_hid == hid;
-        set_master_status(is_master)?;
-        if is_master {
-            println!("this node was elected master");
-        } else {
-            println!("elected {} as master, hid={}", master_addr, master_hid);
+    let mut state = open_state(hid)?;
+    let result = run_daemon(hid, interfaces, &mut state).await;
+    if let Err(e) = &result {
+        if let Err(e) = state.fail(&e.to_string()) {
+            println!("failed to record failure in {}: {}", get_state_path(), e);
         }
-    } else {
-        println!("waiting for master to broadcast connection details");
     }
+    result
+}
+
+/// Opens the transport for the configured discovery mode on the
+/// `interfaces`.
+fn open_transport(port: i32, interfaces: &[String], config: &BootstrapConfig) -> Result<Transport> {
+    let auth = Authenticator::from_file(get_key_path())?;
+    Ok(match config.discovery {
+        Discovery::Broadcast => {
+            let broadcast_addrs = get_broadcast_addrs(port, interfaces)?;
+            Transport::new(broadcast_socket(port as u16)?, broadcast_addrs, auth)
+        }
+        Discovery::MulticastV4 => {
+            let selected = select_interfaces(&list_interfaces()?, interfaces)?;
+            for iface in &selected {
+                println!("joining {} on {} ({})", config.multicast_group, iface.name, iface.addr);
+            }
+            Transport::with_channel(multicast_v4_channel(config.multicast_group, port as u16, &selected)?, auth)
+        }
+        Discovery::MulticastV6 => {
+            let selected = select_link_local_interfaces(&list_link_local_interfaces()?, interfaces)?;
+            for iface in &selected {
+                println!("joining {} on {}", MULTICAST_GROUP_V6, iface.name);
+            }
+            Transport::with_channel(multicast_v6_channel(port as u16, &selected)?, auth)
+        }
+    })
+}
+
+/// Address the master announces for its Kubernetes API. Only needed with
+/// IPv6 multicast, whose announcements come from a link-local address.
+fn get_api_addr(interfaces: &[String], config: &BootstrapConfig) -> Result<Option<IpAddr>> {
+    match config.discovery {
+        Discovery::MulticastV6 => {
+            let selected = select_link_local_interfaces(&list_link_local_interfaces()?, interfaces)?;
+            Ok(Some(routable_v6_addr(&selected)?.into()))
+        }
+        _ => Ok(None),
+    }
+}
+
+async fn run_daemon(hid: Uuid, interfaces: Vec<String>, state: &mut StateFile) -> Result<()> {
+    let config = get_config()?;
+    let role = state.state().role;
+    let mut is_master = role == Some(Role::Master);
+    println!("hid={}, role={}, phase={}", hid, role.map_or("none", |role| role.as_str()), state.state().phase.as_str());
+    match (state.state().phase, state.state().installing) {
+        (Phase::Installing, Some(installing)) => println!("install as {} was interrupted and will be retried", installing.as_str()),
+        (Phase::Failed, _) => println!("previous run failed: {}", state.state().error.as_deref().unwrap_or("unknown error")),
+        _ => {}
+    }
+    let port = get_port()?;
+    let interfaces = if interfaces.is_empty() { &config.interfaces } else { &interfaces };
+    let transport = open_transport(port, interfaces, &config)?;
+    if let Some(capture) = &config.capture {
+        println!("recording traffic to {}", capture.path);
+        transport.set_capture(CaptureWriter::rotating(capture)?);
+    }
+    let mut inbox = Pump::new(&transport)?.spawn();
+    let artifacts = match &config.installer.artifact_dir {
+        Some(dir) => {
+            println!("verifying artifacts in {}", dir);
+            Some(ArtifactCache::open(dir)?)
+        }
+        None => None,
+    };
+    let installer = new_installer(&config.installer, node_name(hid), config.labels.clone(), artifacts.clone());
+    let hooks = spawn_hooks(ExecHooks::new(config.hooks.clone()));
+    let mut term = state.state().term;
     if is_master {
-        Ok(run_master(hid, &mut socket, &broadcast_addr, &mut buf[..])?)
-    } else {
-        Ok(run_agent(hid, &mut socket, &mut buf[..])?)
+        // The agents elect a replacement if this node was down for longer
+        // than their heartbeat timeout, in which case it rejoins as an agent.
+        if let Some((addr, other)) = listen_for_other_master(&mut inbox, hid, term, &config.timing).await? {
+            println!("{} took over as master in term {}, hid={}; demoting this node", addr, other.term, other.hid);
+            blocking(|| installer.uninstall())?;
+            term = other.term;
+            state.update(|state| *state = BootstrapState { term, ..BootstrapState::default() })?;
+            is_master = false;
+        }
+    }
+    // A master that restarts resumes its lease without an election.
+    let mut elect = !is_master;
+    // Served from the first term this node leads until it exits.
+    let mut artifact_source = None;
+    loop {
+        let mut ranking = Vec::new();
+        let mut servers = Vec::new();
+        let mut joined = None;
+        if elect {
+            println!("finding master");
+            state.set_phase(Phase::Discovering)?;
+            // A master that is already announcing itself is joined without
+            // an election, so the cluster never hears from this node until
+            // it asks to join.
+            let (addr, master) = match join_existing_master(&transport, &mut inbox, hid, term, &config.timing).await? {
+                Some(found) => {
+                    term = found.details.term;
+                    let master = (found.addr, found.details.hid);
+                    joined = Some(found);
+                    master
+                }
+                None => {
+                    let elected = elect_master(&transport, &mut inbox, hid, is_master, term, &config, state).await?;
+                    term = elected.term;
+                    ranking = elected.ranking;
+                    servers = elected.servers;
+                    (elected.addr, elected.hid)
+                }
+            };
+            if is_master && master != hid {
+                println!("{} leads term {}, hid={}; demoting this node", addr, term, master);
+                blocking(|| installer.uninstall())?;
+                state.update(|state| *state = BootstrapState { term, ..BootstrapState::default() })?;
+            }
+            is_master = master == hid;
+            if is_master {
+                println!("this node was elected master in term {}", term);
+            } else if joined.is_none() {
+                println!("elected {} as master in term {}, hid={}", addr, term, master);
+            }
+        } else {
+            println!("waiting for master to broadcast connection details");
+        }
+        elect = true;
+        if is_master {
+            // Anything but a server would start a second control plane.
+            if !matches!(state.state().role, Some(Role::Master) | Some(Role::Server)) && servers.iter().any(|server| *server != hid) {
+                return Err(anyhow!("refusing to start a new cluster while {:?} already serve one", servers));
+            }
+            if let (None, Some(cache)) = (artifact_source, &artifacts) {
+                artifact_source = Some(serve_artifact_cache(cache.clone(), config.installer.artifact_port)?);
+            }
+            let details = ConnectionDetails {
+                hid,
+                servers: choose_servers(hid, &state.state().servers, &ranking, config.server_count),
+                artifacts: artifact_source,
+                term,
+                api_addr: get_api_addr(interfaces, &config)?,
+            };
+            term = run_master(details, &transport, &mut inbox, &config, artifacts.clone(), state, &hooks).await?;
+            continue;
+        }
+        let joined = match joined {
+            Some(joined) => joined,
+            None => join_elected_master(&transport, &mut inbox, hid, term, &config).await?,
+        };
+        match run_agent(hid, joined, &mut inbox, &config, artifacts.as_ref(), state, &hooks).await? {
+            AgentExit::NewTerm(later) => {
+                println!("electing master in term {}", later);
+                term = later;
+            }
+            AgentExit::MasterLost(lost) => {
+                term = lost + 1;
+                println!("starting term {} to replace lost master", term);
+            }
+        }
     }
 }
 
-fn disable_systemd_service() -> Result<()> {
+/// Runs `systemctl <action>` on the daemon's unit, such as `stop` or
+/// `start`.
+fn systemd_service(action: &str) -> Result<()> {
     let output = Command::new("sudo")
-        .args(&[
+        .args([
             "systemctl",
-            "stop",
+            action,
             "homesec-bootstrap.service",
         ])
         .output()?;
-    if !output.status.success() {
-        if !std::str::from_utf8(&output.stderr).unwrap().contains("Failed to connect to bus: No such file or directory") {
-            std::io::stdout().write_all(&output.stdout).unwrap();
-            std::io::stderr().write_all(&output.stderr).unwrap();
-            return Err(anyhow!("systemctl command failed with exit code {}", output.status));
-        }
+    if !output.status.success()
+        && !std::str::from_utf8(&output.stderr).unwrap().contains("Failed to connect to bus: No such file or directory") {
+        std::io::stdout().write_all(&output.stdout).unwrap();
+        std::io::stderr().write_all(&output.stderr).unwrap();
+        return Err(anyhow!("systemctl command failed with exit code {}", output.status));
     }
     Ok(())
 }
 
 fn remove_cluster_preferences() -> Result<()> {
-    match std::fs::remove_file("/etc/k3s-master") {
-        Ok(_) => Ok(()),
-        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
-        Err(e) => Err(Error::from(e)),
+    remove_file_if_exists(ROLE_PATH)?;
+    remove_file_if_exists(MASTER_PATH)?;
+    remove_file_if_exists(&get_state_path())
+}
+
+/// Asks the master at `master` to drain and delete this node, and waits
+/// until it confirms.
+async fn request_leave(transport: &Transport, inbox: &mut Inbox, hid: Uuid, master: SocketAddr, timing: &TimingConfig) -> Result<()> {
+    inbox.enter("leaving");
+    let deadline = Instant::now() + timing.leave_timeout;
+    let mut retries = every(timing.leave_retry);
+    loop {
+        tokio::select! {
+            _ = retries.tick() => {
+                println!("asking master {} to drain this node", master);
+                transport.send_to(&Message::Leave(LeaveMessage { hid }), master)?;
+            }
+            received = inbox.recv_until(deadline) => match received? {
+                None => return Err(anyhow!("timed out waiting for master {} to drain this node", master)),
+                Some((addr, Message::Leave(LeaveMessage { hid: node }))) if node == hid && addr.ip() == master.ip() => {
+                    println!("master {} removed this node from the cluster", master);
+                    return Ok(());
+                }
+                Some((_, msg)) => inbox.ignore(&msg),
+            },
+        }
+    }
+}
+
+/// Takes this node out of the cluster: servers drain and delete their
+/// own Node, agents ask the master to, and every peer is told to forget
+/// it.
+async fn leave_cluster(hid: Uuid, state: &BootstrapState, config: &BootstrapConfig, installer: &dyn Installer) -> Result<()> {
+    let transport = open_transport(get_port()?, &config.interfaces, config)?;
+    match state.role {
+        Some(Role::Master) | Some(Role::Server) => blocking(|| remove_node(installer, &node_name(hid)))?,
+        Some(Role::Agent) => {
+            let master = state.master_addr
+                .ok_or_else(|| anyhow!("the master of this node is unknown, so it cannot be drained"))?;
+            let mut inbox = Pump::new(&transport)?.spawn();
+            request_leave(&transport, &mut inbox, hid, master, &config.timing).await?;
+        }
+        None => {}
     }
+    println!("announcing that {} left the cluster", hid);
+    transport.broadcast(&Message::Leave(LeaveMessage { hid }))
+}
+
+/// Fails if this node is the master and the only server while other
+/// nodes are still in the cluster.
+fn check_orphans(hid: Uuid, state: &BootstrapState, installer: &dyn Installer) -> Result<()> {
+    if state.role != Some(Role::Master) || !installer.is_installed() {
+        return Ok(());
+    }
+    let nodes = list_nodes(installer)?;
+    if orphans_agents(&nodes, &node_name(hid)) {
+        return Err(anyhow!(
+            "this node is the only server for {} other nodes, which would be orphaned; use --force to remove it anyway",
+            nodes.len() - 1
+        ));
+    }
+    Ok(())
 }
 
-fn remove_main() -> Result<()> {
-    disable_systemd_service()?;
+async fn remove_main(force: bool) -> Result<()> {
+    let config = get_config()?;
+    let state = StateFile::open(get_state_path())?.state().clone();
+    let installer = new_installer(&config.installer, String::new(), config.labels.clone(), None);
+    let hid = read_hid()?;
+    if let Some(hid) = hid {
+        if let Err(e) = check_orphans(hid, &state, installer.as_ref()) {
+            if !force {
+                return Err(e);
+            }
+            println!("removing anyway: {}", e);
+        }
+    }
+    // The daemon holds the bootstrap port that leaving needs.
+    systemd_service("stop")?;
+    if let Some(hid) = hid {
+        if let Err(e) = leave_cluster(hid, &state, &config, installer.as_ref()).await {
+            if !force {
+                // Still in the cluster, so keep the daemon running.
+                if let Err(e) = systemd_service("start") {
+                    println!("failed to restart the daemon: {}", e);
+                }
+                return Err(anyhow!("failed to leave the cluster: {}; use --force to remove anyway", e));
+            }
+            println!("removing anyway after failing to leave the cluster: {}", e);
+        }
+    }
     remove_cluster_preferences()?;
-    let script_path = "/usr/local/bin/k3s-uninstall.sh";
-    if Path::new(script_path).exists() {
-        let output = Command::new(script_path)
-            .output()?;
-        if !output.status.success() {
-            std::io::stdout().write_all(&output.stdout).unwrap();
-            std::io::stderr().write_all(&output.stderr).unwrap();
-            return Err(anyhow!("{} failed with exit code {}", script_path, output.status));
-        }
-    }
-    let script_path = "/usr/local/bin/k3s-agent-uninstall.sh";
-    if Path::new(script_path).exists() {
-        let output = Command::new(script_path)
-            .output()?;
-        if !output.status.success() {
-            std::io::stdout().write_all(&output.stdout).unwrap();
-            std::io::stderr().write_all(&output.stderr).unwrap();
-            return Err(anyhow!("{} failed with exit code {}", script_path, output.status));
+    installer.uninstall()?;
+    if let Some(hid) = hid {
+        fire_hook(&ExecHooks::new(config.hooks.clone()), &HookEvent {
+            event: HookKind::Removed,
+            hid,
+            role: state.role,
+            master_hid: state.master_hid,
+            master_addr: state.master_addr,
+            term: state.term,
+        });
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct Status {
+    hid: Option<Uuid>,
+    role: Option<Role>,
+    phase: Phase,
+    master_hid: Option<Uuid>,
+    master_addr: Option<SocketAddr>,
+    distribution: Distribution,
+    installed: bool,
+    install_version: Option<String>,
+    /// `None` if systemd could not be asked.
+    daemon_running: Option<bool>,
+    /// Why the daemon last failed.
+    error: Option<String>,
+    peers: Vec<Peer>,
+}
+
+fn is_daemon_running() -> Option<bool> {
+    let output = Command::new("systemctl")
+        .args(["is-active", "homesec-bootstrap.service"])
+        .output()
+        .ok()?;
+    match std::str::from_utf8(&output.stdout).ok()?.trim() {
+        "active" | "activating" | "reloading" => Some(true),
+        "inactive" | "deactivating" | "failed" => Some(false),
+        _ => None,
+    }
+}
+
+/// Passive receiver on the bootstrap port, for inspecting a cluster
+/// without taking part in it.
+struct Listener {
+    socket: UdpSocket,
+    codec: Codec,
+    buf: [u8; MAX_DATAGRAM_SIZE],
+}
+
+impl Listener {
+    fn open(config: &BootstrapConfig, port: u16) -> Result<Self> {
+        let socket = match config.discovery {
+            Discovery::Broadcast => broadcast_socket(port)?,
+            Discovery::MulticastV4 => {
+                let selected = select_interfaces(&list_interfaces()?, &config.interfaces)?;
+                multicast_v4_channel(config.multicast_group, port, &selected)?.socket
+            }
+            Discovery::MulticastV6 => {
+                let selected = select_link_local_interfaces(&list_link_local_interfaces()?, &config.interfaces)?;
+                multicast_v6_channel(port, &selected)?.socket
+            }
+        };
+        Ok(Self {
+            socket,
+            codec: Codec::new(Authenticator::from_file(get_key_path())?),
+            buf: [0; MAX_DATAGRAM_SIZE],
+        })
+    }
+
+    /// Returns the next authenticated message, silently skipping anything
+    /// else, or `None` if nothing is waiting.
+    fn recv(&mut self) -> Result<Option<(SocketAddr, Message)>> {
+        loop {
+            match self.socket.recv_from(&mut self.buf) {
+                Ok((n, addr)) => {
+                    if let Ok(Decoded::Message(msg)) = self.codec.decode(&self.buf[..n]) {
+                        return Ok(Some((addr, msg)));
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(e) => return Err(Error::from(e)),
+            }
+        }
+    }
+}
+
+/// Collects the peers heard on the bootstrap port within `duration`,
+/// without sending anything.
+fn listen_for_peers(config: &BootstrapConfig, port: u16, duration: Duration) -> Result<Vec<Peer>> {
+    let mut listener = Listener::open(config, port)?;
+    let mut table = PeerTable::new();
+    let start = SystemTime::now();
+    while start.elapsed().unwrap_or_default() < duration {
+        match listener.recv()? {
+            Some((addr, msg)) => table.observe(addr, &msg, SystemTime::now()),
+            None => std::thread::sleep(Duration::from_millis(50)),
+        }
+    }
+    Ok(table.peers())
+}
+
+fn print_peers(peers: &[Peer]) {
+    println!("{:<36}  {:<21}  {:>8}  {:>6}  {:<6}  {:>4}  {:>5}  {:>9}", "HID", "ADDRESS", "PRIORITY", "STATIC", "MASTER", "TERM", "VOTES", "LAST SEEN");
+    for peer in peers {
+        let optional = |value: Option<i32>| value.map_or(String::from("-"), |value| value.to_string());
+        println!(
+            "{:<36}  {:<21}  {:>8}  {:>6}  {:<6}  {:>4}  {:>5}  {:>8}s",
+            peer.hid,
+            peer.addr,
+            optional(peer.priority),
+            optional(peer.static_priority),
+            peer.is_master,
+            peer.term,
+            peer.votes,
+            peer.last_seen.elapsed().unwrap_or_default().as_secs(),
+        );
+    }
+}
+
+/// Reports on this node without changing anything. Unlike the daemon it
+/// logs nothing, so that `--json` output can be piped.
+fn status_main(json: bool, listen: Duration) -> Result<()> {
+    let config = load_config()?;
+    let state = StateFile::open(get_state_path())?.state().clone();
+    let installer = new_installer(&config.installer, String::new(), config.labels.clone(), None);
+    let peers = listen_for_peers(&config, read_port()? as u16, listen)?;
+    let status = Status {
+        hid: read_hid()?,
+        role: state.role,
+        phase: state.phase,
+        master_hid: state.master_hid,
+        master_addr: state.master_addr,
+        distribution: config.installer.distribution,
+        installed: installer.is_installed(),
+        install_version: installer.version(),
+        daemon_running: is_daemon_running(),
+        error: state.error,
+        peers,
+    };
+    if json {
+        println!("{}", serde_json::to_string_pretty(&status)?);
+        return Ok(());
+    }
+    let none = || String::from("none");
+    println!("hid:     {}", status.hid.map_or_else(none, |hid| hid.to_string()));
+    println!("role:    {} ({})", status.role.map_or("none", |role| role.as_str()), status.phase.as_str());
+    match (status.master_hid, status.master_addr) {
+        (Some(hid), Some(addr)) => println!("master:  {} at {}", hid, addr),
+        (Some(hid), None) => println!("master:  {} (this node)", hid),
+        _ => println!("master:  unknown"),
+    }
+    println!(
+        "install: {}, {}",
+        status.distribution.as_str(),
+        match (status.installed, &status.install_version) {
+            (true, Some(version)) => format!("installed ({})", version),
+            (true, None) => String::from("installed"),
+            (false, _) => String::from("not installed"),
+        }
+    );
+    println!("daemon:  {}", match status.daemon_running {
+        Some(true) => "running",
+        Some(false) => "stopped",
+        None => "unknown",
+    });
+    if let Some(error) = &status.error {
+        println!("error:   {}", error);
+    }
+    println!();
+    println!("{} peers heard in {:?}:", status.peers.len(), listen);
+    print_peers(&status.peers);
+    Ok(())
+}
+
+fn peers_main(record: Option<String>) -> Result<()> {
+    let config = load_config()?;
+    let port = read_port()?;
+    let mut listener = Listener::open(&config, port as u16)?;
+    let mut capture = match &record {
+        Some(path) => Some(CaptureWriter::open(path)?),
+        None => None,
+    };
+    let mut table = PeerTable::new();
+    let redraw_interval = Duration::from_millis(1000);
+    let mut last_redraw = None;
+    loop {
+        while let Some((addr, msg)) = listener.recv()? {
+            let now = SystemTime::now();
+            table.observe(addr, &msg, now);
+            if let Some(capture) = capture.as_mut() {
+                capture.write(&CaptureRecord::new(now, Direction::Received, addr, msg))?;
+            }
+        }
+        if last_redraw.is_none_or(|t: SystemTime| t.elapsed().unwrap_or_default() >= redraw_interval) {
+            // Clear the terminal and move the cursor home.
+            print!("\x1b[2J\x1b[H");
+            println!("listening on port {}{}", port, record.as_ref().map_or(String::new(), |path| format!(", recording to {}", path)));
+            println!();
+            print_peers(&table.peers());
+            io::stdout().flush()?;
+            last_redraw = Some(SystemTime::now());
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn replay_main(captures: Vec<String>) -> Result<()> {
+    let mut records = Vec::new();
+    for path in &captures {
+        records.extend(read_capture(path)?);
+    }
+    let start = match records.first() {
+        Some(record) => record.system_time(),
+        None => return Err(anyhow!("no records in {}", captures.join(", "))),
+    };
+    let offset = |time: SystemTime| time.duration_since(start).unwrap_or_default().as_secs_f64();
+    let mut replay = Replay::new(start, &load_config()?);
+    for record in &records {
+        let direction = match record.direction {
+            Direction::Sent => "to",
+            Direction::Received => "from",
+        };
+        println!("+{:.3}s {} {} {:?}", offset(record.system_time()), direction, record.addr, record.message);
+        for (time, decision) in replay.feed(record) {
+            match decision {
+                Decision::Vote { addr, hid, term } => println!("+{:.3}s => vote for {}, hid={} in term {}", offset(time), addr, hid, term),
+                Decision::Elected { addr, hid, term } => println!("+{:.3}s => elected {}, hid={} in term {}", offset(time), addr, hid, term),
+                Decision::NewTerm(term) => println!("+{:.3}s => no quorum, moved on to term {}", offset(time), term),
+            }
         }
     }
     Ok(())
 }
 
-fn main() -> Result<()> {
+#[tokio::main]
+async fn main() -> Result<()> {
     let cli = Cli::parse();
     match cli.command.expect("command is required") {
-        Commands::Daemon => daemon_main(),
-        Commands::Remove => remove_main(),
+        Commands::Daemon { interfaces } => daemon_main(interfaces).await,
+        Commands::Remove { force } => remove_main(force).await,
+        Commands::Status { json, listen } => status_main(json, Duration::from_secs(listen)),
+        Commands::Peers { record } => peers_main(record),
+        Commands::Replay { captures } => replay_main(captures),
     }
 }
@@ -0,0 +1,124 @@
+//! DNS-SD advertisement of the elected master, so that laptops and the
+//! GUI can find the cluster without scanning the network, and agents can
+//! find the master when bootstrap broadcasts do not reach them.
+//!
+//! The master publishes one `SERVICE_TYPE` instance pointing at the
+//! Kubernetes API, with TXT records for its hid, its role and the
+//! bootstrap port. Records are not authenticated; agents only use them to
+//! learn where to send a `JoinRequest`, whose answer is.
+
+use crate::installer::API_PORT;
+use anyhow::Result;
+use mdns_sd::{Receiver, ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use uuid::Uuid;
+
+pub const SERVICE_TYPE: &str = "_homesec._tcp.local.";
+
+/// A master found through DNS-SD.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MasterRecord {
+    pub hid: Uuid,
+    pub addrs: Vec<IpAddr>,
+    pub api_port: u16,
+    pub bootstrap_port: u16,
+}
+
+impl MasterRecord {
+    /// Where to send bootstrap messages to the master, preferring IPv4.
+    pub fn bootstrap_addr(&self) -> Option<SocketAddr> {
+        self.addrs.iter()
+            .find(|addr| addr.is_ipv4())
+            .or_else(|| self.addrs.first())
+            .map(|addr| SocketAddr::new(*addr, self.bootstrap_port))
+    }
+}
+
+pub fn txt_properties(hid: Uuid, role: &str, bootstrap_port: u16) -> HashMap<String, String> {
+    let mut properties = HashMap::new();
+    properties.insert("hid".to_string(), hid.to_string());
+    properties.insert("role".to_string(), role.to_string());
+    properties.insert("bootstrap_port".to_string(), bootstrap_port.to_string());
+    properties
+}
+
+/// Reads a resolved instance back into a `MasterRecord`, or `None` if it
+/// is not a master or its records are incomplete.
+pub fn parse_master(properties: &HashMap<String, String>, addrs: Vec<IpAddr>, api_port: u16) -> Option<MasterRecord> {
+    if properties.get("role").map(String::as_str) != Some("master") || addrs.is_empty() {
+        return None;
+    }
+    Some(MasterRecord {
+        hid: properties.get("hid")?.parse().ok()?,
+        addrs,
+        api_port,
+        bootstrap_port: properties.get("bootstrap_port")?.parse().ok()?,
+    })
+}
+
+/// Publishes this node until dropped.
+pub struct Advertiser {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl Advertiser {
+    pub fn start(hid: Uuid, role: &str, bootstrap_port: u16) -> Result<Self> {
+        let daemon = ServiceDaemon::new()?;
+        let info = ServiceInfo::new(
+            SERVICE_TYPE,
+            &format!("homesec-{}", hid),
+            &format!("pi-{}.local.", hid),
+            "",
+            API_PORT,
+            txt_properties(hid, role, bootstrap_port),
+        )?
+        .enable_addr_auto();
+        let fullname = info.get_fullname().to_string();
+        daemon.register(info)?;
+        Ok(Self { daemon, fullname })
+    }
+}
+
+impl Drop for Advertiser {
+    fn drop(&mut self) {
+        let _ = self.daemon.unregister(&self.fullname);
+        let _ = self.daemon.shutdown();
+    }
+}
+
+/// Browses for a master in the background.
+pub struct Browser {
+    daemon: ServiceDaemon,
+    events: Receiver<ServiceEvent>,
+}
+
+impl Browser {
+    pub fn start() -> Result<Self> {
+        let daemon = ServiceDaemon::new()?;
+        let events = daemon.browse(SERVICE_TYPE)?;
+        Ok(Self { daemon, events })
+    }
+
+    /// Returns the first master resolved since the last call, without
+    /// blocking.
+    pub fn poll(&self) -> Option<MasterRecord> {
+        while let Ok(event) = self.events.try_recv() {
+            if let ServiceEvent::ServiceResolved(info) = event {
+                let addrs = info.get_addresses().iter().copied().collect();
+                let properties = info.get_properties().clone().into_property_map_str();
+                if let Some(record) = parse_master(&properties, addrs, info.get_port()) {
+                    return Some(record);
+                }
+            }
+        }
+        None
+    }
+}
+
+impl Drop for Browser {
+    fn drop(&mut self) {
+        let _ = self.daemon.shutdown();
+    }
+}
@@ -0,0 +1,58 @@
+//! Multicast discovery, for networks where IPv4 directed broadcast is
+//! unavailable or filtered. Carries the same framed `Message`s as the
+//! broadcast transport, just to a group instead of a subnet.
+
+use crate::interfaces::{Interface, LinkLocalInterface};
+use crate::transport::Channel;
+use anyhow::Result;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, UdpSocket};
+
+/// Administratively scoped, so routers never forward it off the site.
+pub const DEFAULT_MULTICAST_GROUP_V4: Ipv4Addr = Ipv4Addr::new(239, 255, 43, 1);
+
+/// Link-local scope, so it never leaves the network segment. The group
+/// id spells "HSEC".
+pub const MULTICAST_GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0x4853, 0x4543);
+
+fn bind(domain: Domain, addr: SocketAddr) -> Result<UdpSocket> {
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    if domain == Domain::IPV6 {
+        socket.set_only_v6(true)?;
+    }
+    // Lets a second daemon on the same host, e.g. under test, share the
+    // port.
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+/// Joins `group` on every interface in `ifaces` and sends to it from each.
+pub fn multicast_v4_channel(group: Ipv4Addr, port: u16, ifaces: &[Interface]) -> Result<Channel> {
+    let socket = bind(Domain::IPV4, (Ipv4Addr::UNSPECIFIED, port).into())?;
+    // Nodes count their own appearances, so they must hear themselves.
+    socket.set_multicast_loop_v4(true)?;
+    for iface in ifaces {
+        socket.join_multicast_v4(&group, &iface.addr)?;
+    }
+    Ok(Channel {
+        socket,
+        targets: vec![(group, port).into()],
+        multicast_ifs: ifaces.iter().map(|iface| iface.addr).collect(),
+    })
+}
+
+/// Joins `MULTICAST_GROUP_V6` on every interface in `ifaces` and sends to
+/// it from each, using the scope id to pick the interface.
+pub fn multicast_v6_channel(port: u16, ifaces: &[LinkLocalInterface]) -> Result<Channel> {
+    let socket = bind(Domain::IPV6, (Ipv6Addr::UNSPECIFIED, port).into())?;
+    socket.set_multicast_loop_v6(true)?;
+    for iface in ifaces {
+        socket.join_multicast_v6(&MULTICAST_GROUP_V6, iface.index)?;
+    }
+    let targets = ifaces.iter()
+        .map(|iface| SocketAddrV6::new(MULTICAST_GROUP_V6, port, 0, iface.index).into())
+        .collect();
+    Ok(Channel::new(socket, targets))
+}
@@ -0,0 +1,81 @@
+//! Checks an election from the outside, from the broadcasts of the nodes
+//! taking part, the way the integration tests judge a cluster.
+
+use crate::election::Message;
+use anyhow::{anyhow, Result};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+
+#[derive(Default)]
+pub struct ElectionObserver {
+    /// Nodes that announced themselves.
+    pub appearances: HashSet<SocketAddr>,
+    /// Voters for each candidate, by term.
+    pub votes: HashMap<(u64, SocketAddr), HashSet<SocketAddr>>,
+    /// Latest result announced by each node, with its term.
+    pub results: HashMap<SocketAddr, (u64, SocketAddr)>,
+    /// The master announcing connection details, with its term.
+    pub master: Option<(SocketAddr, u64)>,
+}
+
+impl ElectionObserver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fails on traffic that no healthy election produces: a reset, or a
+    /// second master announcing itself in the same term.
+    pub fn observe(&mut self, addr: SocketAddr, msg: &Message) -> Result<()> {
+        match msg {
+            Message::Reset => return Err(anyhow!("observed unexpected election reset")),
+            // Only the first of each is reported.
+            Message::Appearance(msg) if self.appearances.insert(addr) => {
+                println!("appearance from {}, is_master={}, hid={}, priority={}", addr, msg.is_master, msg.hid, msg.priority);
+            }
+            Message::CastVote(vote) if self.votes.entry((vote.term, vote.addr)).or_default().insert(addr) => {
+                println!("{} cast vote for {} in term {}", addr, vote.addr, vote.term);
+            }
+            Message::ConnectionDetails(details) => match self.master {
+                Some((master, term)) if details.term < term || (master == addr && details.term == term) => {}
+                Some((master, term)) if details.term == term => {
+                    return Err(anyhow!("observed ConnectionDetails from {} and {} in term {}", master, addr, term));
+                }
+                _ => {
+                    println!("observed connection details for master, addr={}, hid={}, term={}", addr, details.hid, details.term);
+                    self.master = Some((addr, details.term));
+                }
+            },
+            Message::ElectionResult(result) => {
+                println!("observed election result, source={}, candidate={}, hid={}, term={}", addr, result.addr, result.hid, result.term);
+                self.results.insert(addr, (result.term, result.addr));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Whether all `count` nodes concluded and the master announced itself.
+    pub fn concluded(&self, count: usize) -> bool {
+        self.results.len() == count && self.master.is_some()
+    }
+
+    /// Checks that all `count` nodes took part and agreed on a master that
+    /// every one of them voted for, returning its address.
+    pub fn check(&self, count: usize) -> Result<SocketAddr> {
+        if self.appearances.len() != count {
+            return Err(anyhow!("only {}/{} nodes broadcasted appearance messages", self.appearances.len(), count));
+        }
+        if self.results.len() != count {
+            return Err(anyhow!("only {}/{} nodes concluded election", self.results.len(), count));
+        }
+        let (master, term) = self.master.ok_or_else(|| anyhow!("failed to get connection details from master"))?;
+        let vote_count = self.votes.get(&(term, master)).map_or(0, |votes| votes.len());
+        if vote_count < count {
+            return Err(anyhow!("master received only {}/{} votes in term {}", vote_count, count, term));
+        }
+        if self.results.values().any(|result| *result != (term, master)) {
+            return Err(anyhow!("nodes disagree on outcome of election"));
+        }
+        Ok(master)
+    }
+}
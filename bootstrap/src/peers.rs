@@ -0,0 +1,127 @@
+//! Passive view of the nodes talking on the bootstrap port, built from
+//! the messages they send.
+
+use crate::election::Message;
+use serde::{Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::time::SystemTime;
+use uuid::Uuid;
+
+/// A node heard on the bootstrap port. Fields only carried by
+/// appearances are unset until one is heard from the node.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Peer {
+    pub hid: Uuid,
+    pub addr: SocketAddr,
+    pub priority: Option<i32>,
+    pub static_priority: Option<i32>,
+    pub master_eligible: Option<bool>,
+    pub is_master: bool,
+    pub labels: BTreeMap<String, String>,
+    /// Latest election term heard from this node.
+    pub term: u64,
+    /// Number of nodes whose latest vote, in the latest term anyone voted
+    /// in, is for this one.
+    pub votes: usize,
+    #[serde(serialize_with = "unix_seconds")]
+    pub last_seen: SystemTime,
+}
+
+fn unix_seconds<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+    let secs = time.duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    serializer.serialize_u64(secs)
+}
+
+#[derive(Default)]
+pub struct PeerTable {
+    peers: HashMap<Uuid, Peer>,
+    /// Hid last heard from each address, so that messages which do not
+    /// carry the sender's hid can still be attributed.
+    addrs: HashMap<SocketAddr, Uuid>,
+    /// Latest vote from each voter's address, with its term.
+    votes: HashMap<SocketAddr, (u64, SocketAddr)>,
+}
+
+impl PeerTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn observe(&mut self, addr: SocketAddr, msg: &Message, now: SystemTime) {
+        match msg {
+            Message::Appearance(msg) => {
+                let peer = self.upsert(msg.hid, addr, now);
+                peer.priority = Some(msg.priority);
+                peer.static_priority = Some(msg.static_priority);
+                peer.master_eligible = Some(msg.master_eligible);
+                peer.is_master = msg.is_master;
+                peer.labels = msg.labels.clone();
+            }
+            Message::ConnectionDetails(details) => {
+                self.upsert(details.hid, addr, now).is_master = true;
+            }
+            Message::JoinRequest(request) => {
+                self.upsert(request.hid, addr, now);
+            }
+            Message::CastVote(vote) => {
+                self.votes.insert(addr, (vote.term, vote.addr));
+                self.touch(addr, now);
+            }
+            Message::Reset | Message::ElectionResult(_) | Message::JoinResponse(_) => {
+                self.touch(addr, now);
+            }
+            Message::Leave(leave) => {
+                if let Some(peer) = self.peers.remove(&leave.hid) {
+                    self.addrs.remove(&peer.addr);
+                    self.votes.remove(&peer.addr);
+                }
+            }
+        }
+        if let Some(term) = msg.term() {
+            if let Some(peer) = self.addrs.get(&addr).copied().and_then(|hid| self.peers.get_mut(&hid)) {
+                peer.term = peer.term.max(term);
+            }
+        }
+    }
+
+    fn upsert(&mut self, hid: Uuid, addr: SocketAddr, now: SystemTime) -> &mut Peer {
+        self.addrs.insert(addr, hid);
+        let peer = self.peers.entry(hid).or_insert_with(|| Peer {
+            hid,
+            addr,
+            priority: None,
+            static_priority: None,
+            master_eligible: None,
+            is_master: false,
+            labels: BTreeMap::new(),
+            term: 0,
+            votes: 0,
+            last_seen: now,
+        });
+        peer.addr = addr;
+        peer.last_seen = now;
+        peer
+    }
+
+    fn touch(&mut self, addr: SocketAddr, now: SystemTime) {
+        if let Some(hid) = self.addrs.get(&addr) {
+            if let Some(peer) = self.peers.get_mut(hid) {
+                peer.last_seen = now;
+            }
+        }
+    }
+
+    /// Every peer heard so far with its vote tally, ordered by hid.
+    pub fn peers(&self) -> Vec<Peer> {
+        let term = self.votes.values().map(|(term, _)| *term).max();
+        let mut peers: Vec<Peer> = self.peers.values()
+            .map(|peer| Peer {
+                votes: self.votes.values().filter(|vote| Some(vote.0) == term && vote.1 == peer.addr).count(),
+                ..peer.clone()
+            })
+            .collect();
+        peers.sort_by_key(|peer| peer.hid);
+        peers
+    }
+}
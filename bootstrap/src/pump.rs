@@ -0,0 +1,181 @@
+//! The daemon's single receive path. One task decodes every datagram as
+//! soon as it arrives and hands the messages, in order, to whichever phase
+//! is running, so that nothing waits out a phase's sleep.
+
+use crate::auth::{AuthError, Authenticator};
+use crate::capture::Direction;
+use crate::election::{Message, SystemClock};
+use crate::transport::{record, SharedCapture, SourceFilter, Transport};
+use crate::wire::{Codec, DecodeError, Decoded, MAX_DATAGRAM_SIZE};
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+
+/// Ticks every `period`, starting now. Ticks missed while a phase was
+/// busy, such as during an install, are not made up in a burst.
+pub fn every(period: Duration) -> Interval {
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    interval
+}
+
+/// Messages decoded but not yet taken by a phase. Once full, the pump
+/// stops reading and further datagrams queue in the socket instead.
+pub const INBOX_CAPACITY: usize = 1024;
+
+/// Receives through a `Transport`'s socket, dropping datagrams that fail
+/// to decode or authenticate.
+pub struct Pump {
+    socket: UdpSocket,
+    codec: Codec,
+    filter: SourceFilter,
+    capture: SharedCapture,
+    buf: [u8; MAX_DATAGRAM_SIZE],
+}
+
+impl Pump {
+    /// Must be called from within a tokio runtime.
+    pub fn new(transport: &Transport) -> Result<Self> {
+        Ok(Self {
+            socket: UdpSocket::from_std(transport.socket().try_clone()?)?,
+            codec: Codec::new(transport.auth().clone()),
+            filter: SourceFilter::new(Arc::new(SystemClock)),
+            capture: transport.capture().clone(),
+            buf: [0; MAX_DATAGRAM_SIZE],
+        })
+    }
+
+    pub fn auth(&self) -> &Authenticator {
+        self.codec.auth()
+    }
+
+    pub fn filter(&self) -> &SourceFilter {
+        &self.filter
+    }
+
+    /// Waits for the next authenticated message.
+    pub async fn recv(&mut self) -> Result<(SocketAddr, Message)> {
+        loop {
+            let (n, addr) = self.socket.recv_from(&mut self.buf).await?;
+            if let Some(msg) = self.decode(n, addr) {
+                record(&self.capture, Direction::Received, addr, &msg);
+                return Ok((addr, msg));
+            }
+        }
+    }
+
+    fn decode(&mut self, n: usize, addr: SocketAddr) -> Option<Message> {
+        if self.filter.is_blocked(&addr) {
+            return None;
+        }
+        match self.codec.decode(&self.buf[..n]) {
+            Ok(Decoded::Message(msg)) => Some(msg),
+            Ok(Decoded::Skipped(header)) => {
+                println!(
+                    "skipping unknown message kind {} from {} (protocol version {})",
+                    header.kind, addr, header.version
+                );
+                None
+            }
+            Err(DecodeError::Auth(AuthError::Replayed)) => {
+                // Duplicates are expected when a sender broadcasts on
+                // several interfaces, so they do not count against it.
+                None
+            }
+            Err(e) => {
+                let dropped = self.filter.record(addr);
+                let stats = self.codec.auth().stats;
+                println!(
+                    "dropping {} packet from {} (dropped={}, unauthenticated={}, replayed={})",
+                    e, addr, dropped, stats.unauthenticated, stats.replayed
+                );
+                None
+            }
+        }
+    }
+
+    /// Receives on a background task until the returned inbox is dropped.
+    pub fn spawn(mut self) -> Inbox {
+        let (tx, rx) = mpsc::channel(INBOX_CAPACITY);
+        let task = tokio::spawn(async move {
+            loop {
+                let received = self.recv().await?;
+                if tx.send(received).await.is_err() {
+                    return Ok(());
+                }
+            }
+        });
+        Inbox {
+            rx,
+            task: Some(task),
+            phase: "starting",
+            ignored: BTreeMap::new(),
+        }
+    }
+}
+
+/// The messages a `Pump` received, for the phase currently running.
+pub struct Inbox {
+    rx: mpsc::Receiver<(SocketAddr, Message)>,
+    task: Option<JoinHandle<Result<()>>>,
+    phase: &'static str,
+    /// Messages the current phase had no use for, by kind.
+    ignored: BTreeMap<&'static str, u64>,
+}
+
+impl Inbox {
+    /// Starts attributing messages to `phase`, and reports what the
+    /// previous phase ignored.
+    pub fn enter(&mut self, phase: &'static str) {
+        if !self.ignored.is_empty() {
+            let ignored: Vec<String> = self.ignored.iter()
+                .map(|(name, count)| format!("{} {}", count, name))
+                .collect();
+            println!("ignored while {}: {}", self.phase, ignored.join(", "));
+            self.ignored.clear();
+        }
+        self.phase = phase;
+    }
+
+    /// Notes that the current phase had no use for `msg`.
+    pub fn ignore(&mut self, msg: &Message) {
+        *self.ignored.entry(msg.name()).or_insert(0) += 1;
+    }
+
+    /// Waits for the next message. Fails if the pump stopped.
+    pub async fn recv(&mut self) -> Result<(SocketAddr, Message)> {
+        match self.rx.recv().await {
+            Some(received) => Ok(received),
+            None => Err(match self.task.take() {
+                Some(task) => match task.await {
+                    Ok(Err(e)) => anyhow!("receive failed: {}", e),
+                    Ok(Ok(())) => anyhow!("receive stopped"),
+                    Err(e) => anyhow!("receive task failed: {}", e),
+                },
+                None => anyhow!("receive stopped"),
+            }),
+        }
+    }
+
+    /// Waits for the next message, or returns `None` at `deadline`.
+    pub async fn recv_until(&mut self, deadline: Instant) -> Result<Option<(SocketAddr, Message)>> {
+        match tokio::time::timeout_at(deadline, self.recv()).await {
+            Ok(received) => Ok(Some(received?)),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+impl Drop for Inbox {
+    fn drop(&mut self) {
+        if let Some(task) = &self.task {
+            task.abort();
+        }
+    }
+}
@@ -0,0 +1,116 @@
+//! Re-runs a node's election from a capture, to show why it voted and
+//! concluded the way it did.
+
+use crate::capture::{CaptureRecord, Direction};
+use crate::config::BootstrapConfig;
+use crate::election::{Clock, Election, ManualClock, Message};
+use crate::timing::TimingConfig;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::SystemTime;
+use uuid::Uuid;
+
+/// What `check_vote` or `check_result` decided.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Decision {
+    Vote { addr: SocketAddr, hid: Uuid, term: u64 },
+    Elected { addr: SocketAddr, hid: Uuid, term: u64 },
+    /// The term passed without a quorum, so the node moved on to this one.
+    NewTerm(u64),
+}
+
+/// Feeds received messages into a fresh `Election` driven by the capture's
+/// timestamps. Sent messages are skipped, since a node hears its own
+/// broadcasts. The election starts at the first record and, like the
+/// daemon, stops deciding once it concludes until it hears from a later
+/// term, when it starts a fresh election with the same settings.
+pub struct Replay {
+    clock: ManualClock,
+    timing: TimingConfig,
+    cluster_size: Option<usize>,
+    election: Election,
+    /// Time of the last check, so that checks happen every
+    /// `election_interval` like in the daemon's election loop.
+    checked: SystemTime,
+    /// Term of the result, once the election has concluded.
+    concluded: Option<u64>,
+}
+
+impl Replay {
+    /// Replays with the same `config` as the daemon that made the capture.
+    pub fn new(start: SystemTime, config: &BootstrapConfig) -> Self {
+        let clock = ManualClock::new(start);
+        Self {
+            election: Self::election(&clock, &config.timing, config.cluster_size, 0),
+            clock,
+            timing: config.timing.clone(),
+            cluster_size: config.cluster_size,
+            checked: start,
+            concluded: None,
+        }
+    }
+
+    fn election(clock: &ManualClock, timing: &TimingConfig, cluster_size: Option<usize>, term: u64) -> Election {
+        let mut election = Election::with_clock(Arc::new(clock.clone()), Box::new(StdRng::seed_from_u64(0)));
+        election.term = term;
+        election.delay = timing.election_delay;
+        election.node_timeout = timing.node_timeout;
+        election.cluster_size = cluster_size;
+        election
+    }
+
+    /// Applies `record`, returning the decisions made up to and including
+    /// it along with the time each was made.
+    pub fn feed(&mut self, record: &CaptureRecord) -> Vec<(SystemTime, Decision)> {
+        let time = record.system_time();
+        let mut decisions = Vec::new();
+        while self.checked + self.timing.election_interval <= time {
+            self.checked += self.timing.election_interval;
+            self.clock.set(self.checked);
+            self.check(&mut decisions);
+        }
+        self.clock.set(time);
+        if record.direction == Direction::Received {
+            if let Some(concluded) = self.concluded {
+                let later = match (&record.message, record.message.term()) {
+                    (Message::Reset, _) => Some(concluded),
+                    (_, Some(term)) if term > concluded => Some(term),
+                    _ => None,
+                };
+                if let Some(term) = later {
+                    self.election = Self::election(&self.clock, &self.timing, self.cluster_size, term);
+                    self.concluded = None;
+                }
+            }
+            if self.concluded.is_none() {
+                if let Err(e) = self.election.process_message(record.addr, &record.message) {
+                    println!("ignoring message from {}: {}", record.addr, e);
+                }
+            }
+        }
+        self.check(&mut decisions);
+        decisions
+    }
+
+    /// Same order as the daemon's election loop.
+    fn check(&mut self, decisions: &mut Vec<(SystemTime, Decision)>) {
+        if self.concluded.is_some() {
+            return;
+        }
+        let now = self.clock.now();
+        let term = self.election.term;
+        if let Some((addr, hid)) = self.election.check_result() {
+            decisions.push((now, Decision::Elected { addr, hid, term }));
+            self.concluded = Some(term);
+            return;
+        }
+        if self.election.term != term {
+            decisions.push((now, Decision::NewTerm(self.election.term)));
+        }
+        if let Some((addr, hid)) = self.election.check_vote() {
+            decisions.push((now, Decision::Vote { addr, hid, term: self.election.term }));
+        }
+    }
+}
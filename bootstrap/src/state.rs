@@ -0,0 +1,225 @@
+//! Progress of the daemon, persisted so that a restart resumes where the
+//! previous run stopped instead of reinstalling from scratch.
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+pub const STATE_PATH: &str = "/var/lib/homesec/bootstrap.json";
+
+/// Part this node plays in the cluster.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    /// Elected node that announces the cluster and hands out join tokens.
+    /// The first master initializes the embedded etcd cluster.
+    Master,
+    /// Additional control plane node.
+    Server,
+    Agent,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Master => "master",
+            Role::Server => "server",
+            Role::Agent => "agent",
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "master" => Ok(Role::Master),
+            "server" => Ok(Role::Server),
+            "agent" => Ok(Role::Agent),
+            _ => Err(anyhow!("unknown role '{}'", s)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Phase {
+    /// Listening for an existing master.
+    Discovering,
+    Electing,
+    /// Running the installer for `BootstrapState::installing`. Finding
+    /// this phase on startup means the install was interrupted.
+    Installing,
+    JoinedAsMaster,
+    JoinedAsServer,
+    JoinedAsAgent,
+    /// The daemon exited with `BootstrapState::error`.
+    Failed,
+}
+
+impl Phase {
+    pub fn joined(role: Role) -> Self {
+        match role {
+            Role::Master => Phase::JoinedAsMaster,
+            Role::Server => Phase::JoinedAsServer,
+            Role::Agent => Phase::JoinedAsAgent,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Phase::Discovering => "discovering",
+            Phase::Electing => "electing",
+            Phase::Installing => "installing",
+            Phase::JoinedAsMaster => "joined-as-master",
+            Phase::JoinedAsServer => "joined-as-server",
+            Phase::JoinedAsAgent => "joined-as-agent",
+            Phase::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct BootstrapState {
+    pub phase: Phase,
+    /// Role of the last install that completed. Unlike `phase`, this
+    /// survives failures, so a server is not demoted by a crash.
+    pub role: Option<Role>,
+    /// Role being installed while `phase` is `Installing`.
+    pub installing: Option<Role>,
+    pub master_hid: Option<Uuid>,
+    /// Bootstrap address of the master, unset on the master itself.
+    pub master_addr: Option<SocketAddr>,
+    /// Version reported by the installer after the last install.
+    pub install_version: Option<String>,
+    /// Why the daemon failed, while `phase` is `Failed`.
+    pub error: Option<String>,
+    /// Latest election term this node took part in, so that a restart
+    /// never rejoins in an earlier one.
+    pub term: u64,
+    /// Control plane this node announced as master, so that after a
+    /// restart, which skips the election, servers still rejoin as servers.
+    pub servers: Vec<Uuid>,
+}
+
+impl Default for BootstrapState {
+    fn default() -> Self {
+        Self {
+            phase: Phase::Discovering,
+            role: None,
+            installing: None,
+            master_hid: None,
+            master_addr: None,
+            install_version: None,
+            error: None,
+            term: 0,
+            servers: Vec::new(),
+        }
+    }
+}
+
+impl BootstrapState {
+    /// Whether an install of `role` under `master` already completed.
+    pub fn has_joined(&self, role: Role, master: Uuid) -> bool {
+        self.phase == Phase::joined(role)
+            && self.role == Some(role)
+            && self.master_hid == Some(master)
+    }
+}
+
+/// A `BootstrapState` that is written back to disk on every update.
+pub struct StateFile {
+    path: PathBuf,
+    state: BootstrapState,
+}
+
+impl StateFile {
+    /// Loads the state at `path`, or the initial state if there is none.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let state = if path.exists() {
+            serde_json::from_str(&std::fs::read_to_string(&path)?)
+                .map_err(|e| anyhow!("invalid state file {}: {}", path.display(), e))?
+        } else {
+            BootstrapState::default()
+        };
+        Ok(Self { path, state })
+    }
+
+    pub fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    pub fn state(&self) -> &BootstrapState {
+        &self.state
+    }
+
+    /// Applies `f` and persists the result. The file is replaced
+    /// atomically, so a crash leaves either the old or the new state.
+    pub fn update<F: FnOnce(&mut BootstrapState)>(&mut self, f: F) -> Result<()> {
+        f(&mut self.state);
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let staged = self.path.with_extension("json.tmp");
+        std::fs::write(&staged, serde_json::to_string_pretty(&self.state)?)?;
+        std::fs::rename(&staged, &self.path)?;
+        Ok(())
+    }
+
+    pub fn set_phase(&mut self, phase: Phase) -> Result<()> {
+        if self.state.phase == phase {
+            return Ok(());
+        }
+        println!("entering phase {}", phase.as_str());
+        self.update(|state| state.phase = phase)
+    }
+
+    /// Records that this node reached `term`. Earlier terms are ignored.
+    pub fn set_term(&mut self, term: u64) -> Result<()> {
+        if term <= self.state.term {
+            return Ok(());
+        }
+        self.update(|state| state.term = term)
+    }
+
+    /// Records the control plane this node announces as master.
+    pub fn set_servers(&mut self, servers: &[Uuid]) -> Result<()> {
+        if self.state.servers == servers {
+            return Ok(());
+        }
+        self.update(|state| state.servers = servers.to_vec())
+    }
+
+    /// Records that an install of `role` is starting.
+    pub fn begin_install(&mut self, role: Role) -> Result<()> {
+        println!("entering phase {} ({})", Phase::Installing.as_str(), role.as_str());
+        self.update(|state| {
+            state.phase = Phase::Installing;
+            state.installing = Some(role);
+            state.error = None;
+        })
+    }
+
+    /// Records that this node joined the cluster of `master_hid` as `role`.
+    pub fn joined(&mut self, role: Role, master_hid: Uuid, master_addr: Option<SocketAddr>, install_version: Option<String>) -> Result<()> {
+        println!("entering phase {}", Phase::joined(role).as_str());
+        self.update(|state| {
+            state.phase = Phase::joined(role);
+            state.role = Some(role);
+            state.installing = None;
+            state.master_hid = Some(master_hid);
+            state.master_addr = master_addr;
+            state.install_version = install_version;
+            state.error = None;
+        })
+    }
+
+    /// Records that the daemon exited with `error`.
+    pub fn fail(&mut self, error: &str) -> Result<()> {
+        self.update(|state| {
+            state.phase = Phase::Failed;
+            state.error = Some(error.to_string());
+        })
+    }
+}
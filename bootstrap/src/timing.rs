@@ -0,0 +1,170 @@
+//! Intervals and timeouts of the election and join phases, so that large
+//! clusters, slow networks and the test harness can each tune them.
+
+use crate::election::{DEFAULT_ELECTION_DELAY, DEFAULT_NODE_TIMEOUT};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Deserializer};
+use std::time::Duration;
+
+/// The `[timing]` table of the bootstrap config. Durations are written as
+/// `500ms`, `10s` or `2m`, or as a bare number of seconds. Each can also
+/// be set by the environment variable named after it in upper case, such
+/// as `NODE_TIMEOUT=45s`, which takes precedence over the file.
+///
+/// ```toml
+/// # Slow Wi-Fi: wait longer before giving up on peers.
+/// [timing]
+/// node_timeout = "60s"
+/// master_timeout = "30s"
+/// ```
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimingConfig {
+    /// How long a starting node listens for an existing master's
+    /// announcement before it calls an election.
+    #[serde(deserialize_with = "duration")]
+    pub discovery_wait: Duration,
+    /// Time after the election starts, and between votes, during which no
+    /// vote is cast.
+    #[serde(deserialize_with = "duration")]
+    pub election_delay: Duration,
+    /// Interval between appearances, and between checks for a vote and a
+    /// result.
+    #[serde(deserialize_with = "duration")]
+    pub election_interval: Duration,
+    /// How long a candidate may stay silent before it is dropped from the
+    /// election.
+    #[serde(deserialize_with = "duration")]
+    pub node_timeout: Duration,
+    /// How long the master's announcements may stop before its nodes elect
+    /// a replacement.
+    #[serde(deserialize_with = "duration")]
+    pub master_timeout: Duration,
+    /// Interval between the master's announcements.
+    #[serde(deserialize_with = "duration")]
+    pub announce_interval: Duration,
+    /// How long a node waits for the master's announcement and then for
+    /// its join token.
+    #[serde(deserialize_with = "duration")]
+    pub join_timeout: Duration,
+    /// Interval between join requests.
+    #[serde(deserialize_with = "duration")]
+    pub join_retry: Duration,
+    /// How long a node waits for an announcement before it also looks for
+    /// the master over mdns.
+    #[serde(deserialize_with = "duration")]
+    pub mdns_fallback_delay: Duration,
+    /// How long a leaving agent waits for the master to drain it.
+    #[serde(deserialize_with = "duration")]
+    pub leave_timeout: Duration,
+    /// Interval between requests to be drained.
+    #[serde(deserialize_with = "duration")]
+    pub leave_retry: Duration,
+}
+
+impl Default for TimingConfig {
+    fn default() -> Self {
+        Self {
+            discovery_wait: Duration::from_secs(5),
+            election_delay: DEFAULT_ELECTION_DELAY,
+            election_interval: Duration::from_millis(1000),
+            node_timeout: DEFAULT_NODE_TIMEOUT,
+            master_timeout: Duration::from_secs(15),
+            announce_interval: Duration::from_millis(1000),
+            join_timeout: Duration::from_secs(150),
+            join_retry: Duration::from_millis(1000),
+            mdns_fallback_delay: Duration::from_secs(10),
+            leave_timeout: Duration::from_secs(300),
+            leave_retry: Duration::from_secs(5),
+        }
+    }
+}
+
+impl TimingConfig {
+    /// Every field along with its name in the config.
+    fn fields_mut(&mut self) -> [(&'static str, &mut Duration); 11] {
+        [
+            ("discovery_wait", &mut self.discovery_wait),
+            ("election_delay", &mut self.election_delay),
+            ("election_interval", &mut self.election_interval),
+            ("node_timeout", &mut self.node_timeout),
+            ("master_timeout", &mut self.master_timeout),
+            ("announce_interval", &mut self.announce_interval),
+            ("join_timeout", &mut self.join_timeout),
+            ("join_retry", &mut self.join_retry),
+            ("mdns_fallback_delay", &mut self.mdns_fallback_delay),
+            ("leave_timeout", &mut self.leave_timeout),
+            ("leave_retry", &mut self.leave_retry),
+        ]
+    }
+
+    /// Overrides each field whose environment variable `var` returns,
+    /// returning the names of the variables that were set.
+    pub fn apply_env<F: Fn(&str) -> Option<String>>(&mut self, var: F) -> Result<Vec<String>> {
+        let mut set = Vec::new();
+        for (name, value) in self.fields_mut() {
+            let name = name.to_uppercase();
+            if let Some(s) = var(&name) {
+                *value = parse_duration(&s).map_err(|e| anyhow!("{}: {}", name, e))?;
+                set.push(name);
+            }
+        }
+        Ok(set)
+    }
+
+    /// Rejects timings with which the daemon could not work, such as a
+    /// timeout no longer than the interval it waits on.
+    pub fn validate(&self) -> Result<()> {
+        let mut fields = self.clone();
+        for (name, value) in fields.fields_mut() {
+            if value.is_zero() {
+                return Err(anyhow!("timing.{} must be greater than zero", name));
+            }
+        }
+        let longer = |name: &str, value: Duration, than: &str, other: Duration| {
+            if value > other {
+                Ok(())
+            } else {
+                Err(anyhow!("timing.{} ({:?}) must be longer than timing.{} ({:?})", name, value, than, other))
+            }
+        };
+        longer("node_timeout", self.node_timeout, "election_interval", self.election_interval)?;
+        longer("master_timeout", self.master_timeout, "announce_interval", self.announce_interval)?;
+        longer("discovery_wait", self.discovery_wait, "announce_interval", self.announce_interval)?;
+        longer("join_timeout", self.join_timeout, "join_retry", self.join_retry)?;
+        longer("join_timeout", self.join_timeout, "mdns_fallback_delay", self.mdns_fallback_delay)?;
+        longer("leave_timeout", self.leave_timeout, "leave_retry", self.leave_retry)?;
+        Ok(())
+    }
+}
+
+/// Parses `500ms`, `10s`, `2m` or a bare number of seconds.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let invalid = || anyhow!("invalid duration {:?}; expected e.g. 500ms, 10s or 2m", s);
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let number = number.parse::<u64>().map_err(|_| invalid())?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(number)),
+        "s" => Ok(Duration::from_secs(number)),
+        "m" => number.checked_mul(60).map(Duration::from_secs).ok_or_else(|| anyhow!("duration {:?} is too long", s)),
+        _ => Err(invalid()),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawDuration {
+    Seconds(u64),
+    Text(String),
+}
+
+pub(crate) fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    match RawDuration::deserialize(deserializer)? {
+        RawDuration::Seconds(secs) => Ok(Duration::from_secs(secs)),
+        RawDuration::Text(s) => parse_duration(&s).map_err(serde::de::Error::custom),
+    }
+}
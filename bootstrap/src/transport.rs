@@ -0,0 +1,244 @@
+use crate::auth::Authenticator;
+use crate::capture::{CaptureRecord, CaptureWriter, Direction};
+use crate::election::{Clock, Message};
+use crate::wire::Codec;
+use anyhow::Result;
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// Number of bad datagrams a source may send within `BAD_PACKET_WINDOW`
+/// before it is ignored for `BLOCK_DURATION`.
+pub const BAD_PACKET_LIMIT: u32 = 20;
+pub const BAD_PACKET_WINDOW: Duration = Duration::from_secs(10);
+pub const BLOCK_DURATION: Duration = Duration::from_secs(60);
+/// Sources a `SourceFilter` keeps track of at most. Beyond that, bad
+/// datagrams from new sources are still dropped but not counted.
+pub const MAX_TRACKED_SOURCES: usize = 1024;
+
+#[derive(Clone, Debug)]
+pub struct SourceStats {
+    /// Total datagrams from this source that were dropped.
+    pub dropped: u64,
+    window_start: SystemTime,
+    window_count: u32,
+    blocked_until: Option<SystemTime>,
+}
+
+impl SourceStats {
+    /// Whether the source is neither blocked nor within a window of bad
+    /// datagrams, so forgetting it loses nothing that limits it.
+    fn is_idle(&self, now: SystemTime) -> bool {
+        !matches!(self.blocked_until, Some(until) if now < until)
+            && now.duration_since(self.window_start).unwrap_or_default() > BAD_PACKET_WINDOW
+    }
+}
+
+/// Counts bad datagrams per source and rate-limits sources that keep
+/// sending them. Sources are told apart by IP alone, so that a sender
+/// cannot shed its record by changing port.
+pub struct SourceFilter {
+    sources: HashMap<IpAddr, SourceStats>,
+    clock: Arc<dyn Clock>,
+}
+
+impl SourceFilter {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            sources: HashMap::new(),
+            clock,
+        }
+    }
+
+    pub fn stats(&self, addr: &SocketAddr) -> Option<&SourceStats> {
+        self.sources.get(&addr.ip())
+    }
+
+    /// Returns true if datagrams from `addr` should currently be dropped
+    /// without being decoded.
+    pub fn is_blocked(&mut self, addr: &SocketAddr) -> bool {
+        let now = self.clock.now();
+        match self.sources.get_mut(&addr.ip()) {
+            Some(stats) => match stats.blocked_until {
+                Some(until) if now < until => {
+                    stats.dropped += 1;
+                    true
+                }
+                Some(_) => {
+                    println!("no longer ignoring {}", addr.ip());
+                    stats.blocked_until = None;
+                    stats.window_start = now;
+                    stats.window_count = 0;
+                    false
+                }
+                None => false,
+            },
+            None => false,
+        }
+    }
+
+    /// Records a bad datagram from `addr`, returning the number dropped
+    /// from it so far.
+    pub fn record(&mut self, addr: SocketAddr) -> u64 {
+        let now = self.clock.now();
+        if !self.sources.contains_key(&addr.ip()) {
+            self.sources.retain(|_, stats| !stats.is_idle(now));
+            if self.sources.len() >= MAX_TRACKED_SOURCES {
+                return 1;
+            }
+        }
+        let stats = self.sources.entry(addr.ip()).or_insert(SourceStats {
+            dropped: 0,
+            window_start: now,
+            window_count: 0,
+            blocked_until: None,
+        });
+        stats.dropped += 1;
+        if now.duration_since(stats.window_start).unwrap_or_default() > BAD_PACKET_WINDOW {
+            stats.window_start = now;
+            stats.window_count = 0;
+        }
+        stats.window_count += 1;
+        if stats.window_count >= BAD_PACKET_LIMIT {
+            println!(
+                "ignoring {} for {:?} after {} bad packets in {:?}",
+                addr.ip(), BLOCK_DURATION, stats.window_count, BAD_PACKET_WINDOW
+            );
+            stats.blocked_until = Some(now + BLOCK_DURATION);
+        }
+        stats.dropped
+    }
+}
+
+/// Binds the broadcast port on every interface. The address is shared,
+/// so that `status` can listen alongside a running daemon.
+pub fn broadcast_socket(port: u16) -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_broadcast(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)).into())?;
+    Ok(socket.into())
+}
+
+/// A bound socket and the addresses `Transport::broadcast` sends to
+/// through it.
+pub struct Channel {
+    pub socket: UdpSocket,
+    pub targets: Vec<SocketAddr>,
+    /// Local addresses to send IPv4 multicast from. Each multicast target
+    /// is sent once per address, since the kernel otherwise only uses the
+    /// interface of the default route.
+    pub multicast_ifs: Vec<Ipv4Addr>,
+}
+
+impl Channel {
+    pub fn new(socket: UdpSocket, targets: Vec<SocketAddr>) -> Self {
+        Self {
+            socket,
+            targets,
+            multicast_ifs: Vec::new(),
+        }
+    }
+
+    fn send(&self, data: &[u8], target: &SocketAddr) -> io::Result<()> {
+        match target {
+            SocketAddr::V4(v4) if v4.ip().is_multicast() && !self.multicast_ifs.is_empty() => {
+                for iface in &self.multicast_ifs {
+                    SockRef::from(&self.socket).set_multicast_if_v4(iface)?;
+                    self.socket.send_to(data, target)?;
+                }
+            }
+            _ => {
+                self.socket.send_to(data, target)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Capture shared by the sending `Transport` and the `Pump` receiving
+/// through the same socket.
+pub(crate) type SharedCapture = Arc<Mutex<Option<CaptureWriter>>>;
+
+/// Records `msg` to `capture`, if there is one.
+pub(crate) fn record(capture: &SharedCapture, direction: Direction, addr: SocketAddr, msg: &Message) {
+    let mut capture = capture.lock().unwrap();
+    if let Some(writer) = capture.as_mut() {
+        let record = CaptureRecord::new(SystemTime::now(), direction, addr, msg.clone());
+        if let Err(e) = writer.write(&record) {
+            // Losing the capture must not take the node down with it.
+            println!("failed to write capture, no longer recording: {}", e);
+            *capture = None;
+        }
+    }
+}
+
+/// Broadcast or multicast socket that frames and seals every outgoing
+/// `Message`. Messages are received through a `Pump` on the same socket.
+pub struct Transport {
+    channel: Channel,
+    codec: Codec,
+    capture: SharedCapture,
+}
+
+impl Transport {
+    pub fn new(socket: UdpSocket, broadcast_addrs: Vec<SocketAddr>, auth: Authenticator) -> Self {
+        Self::with_channel(Channel::new(socket, broadcast_addrs), auth)
+    }
+
+    pub fn with_channel(channel: Channel, auth: Authenticator) -> Self {
+        Self {
+            channel,
+            codec: Codec::new(auth),
+            capture: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Records every message sent or received from now on to `capture`.
+    pub fn set_capture(&self, capture: CaptureWriter) {
+        *self.capture.lock().unwrap() = Some(capture);
+    }
+
+    pub(crate) fn capture(&self) -> &SharedCapture {
+        &self.capture
+    }
+
+    pub(crate) fn socket(&self) -> &UdpSocket {
+        &self.channel.socket
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.channel.socket.local_addr()?)
+    }
+
+    pub fn broadcast_addrs(&self) -> &[SocketAddr] {
+        &self.channel.targets
+    }
+
+    /// Sends `msg` to every target, stopping at the first failure. Every
+    /// copy carries the same nonce, so a receiver that hears it over more
+    /// than one path only accepts the first.
+    pub fn broadcast(&self, msg: &Message) -> Result<()> {
+        let encoded = self.codec.encode(msg)?;
+        for target in &self.channel.targets {
+            self.channel.send(&encoded, target)?;
+            record(&self.capture, Direction::Sent, *target, msg);
+        }
+        Ok(())
+    }
+
+    pub fn send_to(&self, msg: &Message, addr: SocketAddr) -> Result<()> {
+        let encoded = self.codec.encode(msg)?;
+        self.channel.socket.send_to(&encoded[..], addr)?;
+        record(&self.capture, Direction::Sent, addr, msg);
+        Ok(())
+    }
+
+    pub fn auth(&self) -> &Authenticator {
+        self.codec.auth()
+    }
+}
@@ -0,0 +1,223 @@
+//! Framing for bootstrap datagrams.
+//!
+//! Every datagram starts with an 8 byte header in the clear:
+//!
+//! | bytes | field                          |
+//! |-------|--------------------------------|
+//! | 0..4  | `MAGIC`                        |
+//! | 4..6  | protocol version, big endian   |
+//! | 6..8  | message kind, big endian       |
+//!
+//! followed by the authenticated envelope from `auth`, whose payload is
+//! the bincode encoding of the message body alone (not the `Message`
+//! enum), so adding a variant never shifts the encoding of another.
+//!
+//! Compatibility rules: a body may only grow by appending fields, which
+//! older receivers ignore as trailing bytes. Any other change to a body
+//! needs a new kind. Receivers skip kinds they do not know.
+
+use crate::auth::{AuthError, Authenticator};
+use crate::election::*;
+use anyhow::{anyhow, Result};
+use bincode::Options;
+use serde::de::{Deserialize, DeserializeOwned};
+use serde::Serialize;
+use std::fmt;
+
+pub const MAGIC: [u8; 4] = *b"HSEC";
+
+/// Version of the protocol spoken by this build. Bump it whenever a
+/// body gains fields or a kind is added.
+pub const PROTOCOL_VERSION: u16 = 1;
+
+pub const HEADER_LEN: usize = 8;
+
+/// Largest datagram a node sends or accepts.
+pub const MAX_DATAGRAM_SIZE: usize = 8192;
+
+pub mod kind {
+    pub const APPEARANCE: u16 = 1;
+    pub const CAST_VOTE: u16 = 2;
+    pub const RESET: u16 = 3;
+    pub const ELECTION_RESULT: u16 = 4;
+    pub const CONNECTION_DETAILS: u16 = 5;
+    pub const JOIN_REQUEST: u16 = 6;
+    pub const JOIN_RESPONSE: u16 = 7;
+    pub const LEAVE: u16 = 8;
+}
+
+impl Message {
+    pub fn kind(&self) -> u16 {
+        match self {
+            Message::Appearance(_) => kind::APPEARANCE,
+            Message::CastVote(_) => kind::CAST_VOTE,
+            Message::Reset => kind::RESET,
+            Message::ElectionResult(_) => kind::ELECTION_RESULT,
+            Message::ConnectionDetails(_) => kind::CONNECTION_DETAILS,
+            Message::JoinRequest(_) => kind::JOIN_REQUEST,
+            Message::JoinResponse(_) => kind::JOIN_RESPONSE,
+            Message::Leave(_) => kind::LEAVE,
+        }
+    }
+
+    /// Kind of the message as it appears in logs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Message::Appearance(_) => "appearance",
+            Message::CastVote(_) => "cast-vote",
+            Message::Reset => "reset",
+            Message::ElectionResult(_) => "election-result",
+            Message::ConnectionDetails(_) => "connection-details",
+            Message::JoinRequest(_) => "join-request",
+            Message::JoinResponse(_) => "join-response",
+            Message::Leave(_) => "leave",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Header {
+    pub version: u16,
+    pub kind: u16,
+}
+
+impl Header {
+    fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut header = [0u8; HEADER_LEN];
+        header[..4].copy_from_slice(&MAGIC);
+        header[4..6].copy_from_slice(&self.version.to_be_bytes());
+        header[6..8].copy_from_slice(&self.kind.to_be_bytes());
+        header
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() < HEADER_LEN || data[..4] != MAGIC {
+            return None;
+        }
+        Some(Self {
+            version: u16::from_be_bytes([data[4], data[5]]),
+            kind: u16::from_be_bytes([data[6], data[7]]),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum Decoded {
+    Message(Message),
+    /// A well-formed datagram of a kind this build does not understand.
+    Skipped(Header),
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    /// Not a bootstrap datagram, or a body that does not match its kind.
+    Malformed,
+    Auth(AuthError),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Malformed => write!(f, "malformed"),
+            DecodeError::Auth(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Same encoding as `bincode::deserialize`, but never reads past
+/// `MAX_DATAGRAM_SIZE`, so a corrupt length prefix cannot make the
+/// decoder allocate more than a datagram could hold.
+pub(crate) fn deserialize<'a, T: Deserialize<'a>>(data: &'a [u8]) -> bincode::Result<T> {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(MAX_DATAGRAM_SIZE as u64)
+        .deserialize(data)
+}
+
+fn body<T: DeserializeOwned>(payload: &[u8]) -> std::result::Result<T, DecodeError> {
+    deserialize(payload).map_err(|_| DecodeError::Malformed)
+}
+
+fn encode_body(msg: &Message) -> Result<Vec<u8>> {
+    fn enc<T: Serialize>(body: &T) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(body)?)
+    }
+    match msg {
+        Message::Appearance(m) => enc(m),
+        Message::CastVote(m) => enc(m),
+        Message::Reset => Ok(Vec::new()),
+        Message::ElectionResult(m) => enc(m),
+        Message::ConnectionDetails(m) => enc(m),
+        Message::JoinRequest(m) => enc(m),
+        Message::JoinResponse(m) => enc(m),
+        Message::Leave(m) => enc(m),
+    }
+}
+
+fn is_known(kind: u16) -> bool {
+    (kind::APPEARANCE..=kind::LEAVE).contains(&kind)
+}
+
+/// Decodes the body of a known kind.
+fn decode_body(kind: u16, payload: &[u8]) -> std::result::Result<Message, DecodeError> {
+    Ok(match kind {
+        kind::APPEARANCE => Message::Appearance(body(payload)?),
+        kind::CAST_VOTE => Message::CastVote(body(payload)?),
+        kind::RESET => Message::Reset,
+        kind::ELECTION_RESULT => Message::ElectionResult(body(payload)?),
+        kind::CONNECTION_DETAILS => Message::ConnectionDetails(body(payload)?),
+        kind::JOIN_REQUEST => Message::JoinRequest(body(payload)?),
+        kind::JOIN_RESPONSE => Message::JoinResponse(body(payload)?),
+        kind::LEAVE => Message::Leave(body(payload)?),
+        _ => return Err(DecodeError::Malformed),
+    })
+}
+
+/// Frames, seals and decodes `Message`s.
+pub struct Codec {
+    auth: Authenticator,
+}
+
+impl Codec {
+    pub fn new(auth: Authenticator) -> Self {
+        Self { auth }
+    }
+
+    pub fn auth(&self) -> &Authenticator {
+        &self.auth
+    }
+
+    pub fn auth_mut(&mut self) -> &mut Authenticator {
+        &mut self.auth
+    }
+
+    pub fn encode(&self, msg: &Message) -> Result<Vec<u8>> {
+        let header = Header {
+            version: PROTOCOL_VERSION,
+            kind: msg.kind(),
+        }
+        .encode();
+        let envelope = self.auth.seal(&header, encode_body(msg)?)?;
+        if HEADER_LEN + envelope.len() > MAX_DATAGRAM_SIZE {
+            return Err(anyhow!("encoded {:?} exceeds {} bytes", msg, MAX_DATAGRAM_SIZE));
+        }
+        let mut data = Vec::with_capacity(HEADER_LEN + envelope.len());
+        data.extend_from_slice(&header);
+        data.extend_from_slice(&envelope);
+        Ok(data)
+    }
+
+    pub fn decode(&mut self, data: &[u8]) -> std::result::Result<Decoded, DecodeError> {
+        let header = Header::decode(data).ok_or(DecodeError::Malformed)?;
+        if !is_known(header.kind) {
+            return Ok(Decoded::Skipped(header));
+        }
+        let payload = self.auth
+            .open(&data[..HEADER_LEN], &data[HEADER_LEN..])
+            .map_err(DecodeError::Auth)?;
+        Ok(Decoded::Message(decode_body(header.kind, &payload)?))
+    }
+}
@@ -0,0 +1,91 @@
+mod scratch;
+
+use homesec_bootstrap::*;
+use scratch::*;
+use sha2::{Digest, Sha256};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Writes a cache holding `files` and a matching sums file.
+fn write_cache(dir: &Path, files: &[(&str, &[u8])]) {
+    let mut sums = String::new();
+    for (name, contents) in files {
+        std::fs::write(dir.join(name), contents).unwrap();
+        sums += &format!("{}  {}\n", hex(&Sha256::digest(contents)), name);
+    }
+    std::fs::write(dir.join(SUMS_FILE), sums).unwrap();
+}
+
+const FILES: &[(&str, &[u8])] = &[
+    ("k3s", b"binary"),
+    ("install.sh", b"#!/bin/sh\n"),
+    ("k3s-airgap-images-arm64.tar.zst", b"images"),
+];
+
+#[test]
+fn parses_sha256sum_output() {
+    let digest = "a".repeat(64);
+    let sums = format!("{d}  k3s\n{d} *install.sh\n\n{d}  k3s-airgap-images-arm64.tar\n", d = digest);
+    let entries = parse_sums(&sums).unwrap();
+    assert_eq!(entries.len(), 3);
+    assert_eq!(entries[1], ("install.sh".to_string(), digest.clone()));
+    assert!(parse_sums(&format!("{d}  k3s\n{d}  install.sh\n", d = digest)).is_err(), "images are required");
+    assert!(parse_sums(&format!("{d}  ../k3s\n", d = digest)).is_err());
+    assert!(parse_sums("nothex  k3s\n").is_err());
+}
+
+#[test]
+fn rejects_tampered_artifacts() {
+    let dir = scratch_dir("artifacts", "tampered");
+    write_cache(&dir, FILES);
+    let cache = ArtifactCache::open(&dir).unwrap();
+    assert_eq!(cache.images(), vec![dir.join("k3s-airgap-images-arm64.tar.zst")]);
+    std::fs::write(dir.join("k3s"), b"backdoor").unwrap();
+    assert!(ArtifactCache::open(&dir).is_err());
+    std::fs::remove_file(dir.join("k3s")).unwrap();
+    assert!(ArtifactCache::open(&dir).is_err());
+}
+
+#[test]
+fn agents_fetch_the_announced_cache() {
+    let (master, agent) = (scratch_dir("artifacts", "master"), scratch_dir("artifacts", "agent"));
+    write_cache(&master, FILES);
+    let cache = ArtifactCache::open(&master).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let source = cache.source(addr.port());
+    serve_artifacts(cache, listener);
+    assert!(ArtifactCache::fetch(addr, [0; 32], &agent).is_err(), "accepted sums with the wrong digest");
+    let fetched = ArtifactCache::fetch(addr, source.sums, &agent).unwrap();
+    assert_eq!(fetched.files.len(), FILES.len());
+    for (name, contents) in FILES {
+        assert_eq!(&std::fs::read(agent.join(name)).unwrap()[..], *contents);
+    }
+    // What was fetched verifies on its own, e.g. after a restart.
+    ArtifactCache::open(&agent).unwrap();
+}
+
+#[test]
+fn turns_away_connections_over_the_cap() {
+    let (master, agent) = (scratch_dir("artifacts", "busy"), scratch_dir("artifacts", "busy-agent"));
+    write_cache(&master, FILES);
+    let cache = ArtifactCache::open(&master).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let source = cache.source(addr.port());
+    serve_artifacts(cache, listener);
+    let idle: Vec<TcpStream> = (0..MAX_ARTIFACT_CONNECTIONS).map(|_| TcpStream::connect(addr).unwrap()).collect();
+    let err = ArtifactCache::fetch(addr, source.sums, &agent).unwrap_err();
+    assert!(err.to_string().contains("503"), "{}", err);
+    drop(idle);
+    // The slots free up once the server sees the idle connections close.
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    while ArtifactCache::fetch(addr, source.sums, &agent).is_err() {
+        assert!(std::time::Instant::now() < deadline, "slots were not released");
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+}
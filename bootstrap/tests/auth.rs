@@ -0,0 +1,79 @@
+use homesec_bootstrap::*;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+const KEY: &[u8] = b"0123456789abcdef0123456789abcdef";
+const HEADER: &[u8] = b"HSEC\0\x01\0\x03";
+
+fn authenticator(key: &[u8], clock: &ManualClock) -> Authenticator {
+    Authenticator::with_clock(key.to_vec(), Arc::new(clock.clone())).unwrap()
+}
+
+fn clock() -> ManualClock {
+    ManualClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000))
+}
+
+#[test]
+fn opens_sealed_messages() {
+    let clock = clock();
+    let sender = authenticator(KEY, &clock);
+    let mut receiver = authenticator(KEY, &clock);
+    let sealed = sender.seal(HEADER, b"payload".to_vec()).unwrap();
+    assert_eq!(receiver.open(HEADER, &sealed).unwrap(), b"payload");
+}
+
+#[test]
+fn rejects_wrong_key_and_tampering() {
+    let clock = clock();
+    let sender = authenticator(b"another key entirely, not ours", &clock);
+    let mut receiver = authenticator(KEY, &clock);
+    let sealed = sender.seal(HEADER, b"payload".to_vec()).unwrap();
+    assert!(matches!(receiver.open(HEADER, &sealed), Err(AuthError::Unauthenticated)));
+
+    let sender = authenticator(KEY, &clock);
+    let mut sealed = sender.seal(HEADER, b"payload".to_vec()).unwrap();
+    let last = sealed.len() - 1;
+    sealed[last] ^= 1;
+    assert!(matches!(receiver.open(HEADER, &sealed), Err(AuthError::Unauthenticated)));
+
+    let sealed = sender.seal(HEADER, b"payload".to_vec()).unwrap();
+    assert!(matches!(receiver.open(b"HSEC\0\x01\0\x04", &sealed), Err(AuthError::Unauthenticated)));
+
+    assert!(matches!(receiver.open(HEADER, b"payload"), Err(AuthError::Unauthenticated)));
+    assert_eq!(receiver.stats.unauthenticated, 4);
+}
+
+#[test]
+fn rejects_replays() {
+    let clock = clock();
+    let sender = authenticator(KEY, &clock);
+    let mut receiver = authenticator(KEY, &clock);
+    let sealed = sender.seal(HEADER, b"payload".to_vec()).unwrap();
+    assert!(receiver.open(HEADER, &sealed).is_ok());
+    assert!(matches!(receiver.open(HEADER, &sealed), Err(AuthError::Replayed)));
+
+    let stale = sender.seal(HEADER, b"payload".to_vec()).unwrap();
+    clock.advance(receiver.max_skew + Duration::from_secs(1));
+    assert!(matches!(receiver.open(HEADER, &stale), Err(AuthError::Replayed)));
+    assert_eq!(receiver.stats.replayed, 2);
+}
+
+#[test]
+fn rejects_short_keys() {
+    assert!(Authenticator::new(b"short".to_vec()).is_err());
+}
+
+#[test]
+fn join_token_only_opens_for_its_agent() {
+    let clock = clock();
+    let auth = authenticator(KEY, &clock);
+    let (agent, other, master) = (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+    let (nonce, sealed) = auth.seal_token(agent, master, "K10::server:secret").unwrap();
+    assert!(!sealed.windows(6).any(|w| w == b"secret"));
+    assert_eq!(auth.open_token(agent, master, &nonce, &sealed).unwrap(), "K10::server:secret");
+    assert!(auth.open_token(other, master, &nonce, &sealed).is_err());
+    assert!(auth.open_token(agent, other, &nonce, &sealed).is_err());
+    let foreign = authenticator(b"another key entirely, not ours", &clock);
+    assert!(foreign.open_token(agent, master, &nonce, &sealed).is_err());
+}
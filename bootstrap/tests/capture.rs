@@ -0,0 +1,71 @@
+mod scratch;
+
+use homesec_bootstrap::*;
+use scratch::*;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+#[test]
+fn round_trips_records_across_writers() {
+    let path = scratch("capture", "round-trip");
+    let addr: SocketAddr = "10.0.0.1:43000".parse().unwrap();
+    let time = SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+    let records = vec![
+        CaptureRecord::new(time, Direction::Received, addr, Message::CastVote(CastVote {
+            addr,
+            hid: Uuid::new_v4(),
+            term: 1,
+        })),
+        CaptureRecord::new(time, Direction::Sent, addr, Message::JoinResponse(JoinResponse {
+            hid: Uuid::new_v4(),
+            master: Uuid::new_v4(),
+            nonce: [7; 12],
+            token: vec![1, 2, 3],
+        })),
+        CaptureRecord::new(time + Duration::from_secs(1), Direction::Received, addr, Message::Reset),
+    ];
+    CaptureWriter::open(&path).unwrap().write(&records[0]).unwrap();
+    // Reopening appends rather than truncating.
+    let mut writer = CaptureWriter::open(&path).unwrap();
+    writer.write(&records[1]).unwrap();
+    writer.write(&records[2]).unwrap();
+    let read = read_capture(&path).unwrap();
+    assert_eq!(read, records);
+    assert_eq!(read[0].system_time(), time);
+}
+
+#[test]
+fn reports_the_line_of_a_bad_record() {
+    let path = scratch("capture", "bad");
+    std::fs::write(&path, "\n{\"time\": 1}\n").unwrap();
+    let err = read_capture(&path).unwrap_err().to_string();
+    assert!(err.contains(":2:"), "{}", err);
+}
+
+#[test]
+fn rotates_and_keeps_the_newest_captures() {
+    let dir = std::env::temp_dir().join(format!("homesec-capture-rotate-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let config = CaptureConfig {
+        path: dir.join("capture.jsonl").to_str().unwrap().to_string(),
+        max_bytes: 1,
+        keep: 2,
+    };
+    let addr: SocketAddr = "10.0.0.1:43000".parse().unwrap();
+    let mut writer = CaptureWriter::rotating(&config).unwrap();
+    for second in 0..4 {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(second);
+        writer.write(&CaptureRecord::new(time, Direction::Received, addr, Message::Reset)).unwrap();
+    }
+    // Every record fills a capture, so the last two survive in rotation
+    // order and the current capture is empty.
+    let seconds = |name: &str| read_capture(dir.join(name)).unwrap()
+        .iter()
+        .map(|record| record.time / 1000)
+        .collect::<Vec<_>>();
+    assert_eq!(seconds("capture.jsonl"), Vec::<u64>::new());
+    assert_eq!(seconds("capture.jsonl.1"), vec![3]);
+    assert_eq!(seconds("capture.jsonl.2"), vec![2]);
+    assert!(!dir.join("capture.jsonl.3").exists());
+}
@@ -0,0 +1,128 @@
+use homesec_bootstrap::*;
+use std::time::Duration;
+
+#[test]
+fn parses_config_file() {
+    let config = BootstrapConfig::parse(
+        r#"
+        priority = 10
+        master_eligible = false
+        server_count = 5
+        cluster_size = 6
+
+        [labels]
+        "homesec/storage" = "ssd"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.priority, 10);
+    assert!(!config.master_eligible);
+    assert_eq!(config.server_count, 5);
+    assert_eq!(config.cluster_size, Some(6));
+    assert_eq!(config.labels["homesec/storage"], "ssd");
+}
+
+#[test]
+fn defaults_missing_fields() {
+    assert_eq!(BootstrapConfig::parse("").unwrap(), BootstrapConfig::default());
+    assert!(BootstrapConfig::load("/nonexistent/bootstrap.toml").unwrap().master_eligible);
+}
+
+#[test]
+fn rejects_unknown_fields() {
+    assert!(BootstrapConfig::parse("priorty = 10").is_err());
+}
+
+#[test]
+fn requires_a_server() {
+    assert_eq!(BootstrapConfig::default().server_count, 3);
+    let err = BootstrapConfig::parse("server_count = 0").unwrap_err();
+    assert!(err.to_string().contains("server_count"), "{}", err);
+    assert_eq!(BootstrapConfig::default().cluster_size, None);
+    assert!(BootstrapConfig::parse("cluster_size = 0").is_err());
+}
+
+#[test]
+fn parses_discovery_mode() {
+    assert_eq!(BootstrapConfig::default().discovery, Discovery::Broadcast);
+    let config = BootstrapConfig::parse(
+        r#"
+        discovery = "multicast-v4"
+        multicast_group = "239.1.2.3"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.discovery, Discovery::MulticastV4);
+    assert_eq!(config.multicast_group, std::net::Ipv4Addr::new(239, 1, 2, 3));
+    assert!(BootstrapConfig::parse(r#"discovery = "carrier-pigeon""#).is_err());
+}
+
+#[test]
+fn enables_capture_only_when_configured() {
+    assert_eq!(BootstrapConfig::default().capture, None);
+    let config = BootstrapConfig::parse("[capture]\nkeep = 2\n").unwrap();
+    let capture = config.capture.unwrap();
+    assert_eq!(capture.keep, 2);
+    assert_eq!(capture.max_bytes, CaptureConfig::default().max_bytes);
+}
+
+#[test]
+fn parses_timing_durations() {
+    let config = BootstrapConfig::parse(
+        r#"
+        [timing]
+        election_delay = "2s"
+        join_retry = "500ms"
+        node_timeout = 45
+        leave_timeout = "10m"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.timing.election_delay, Duration::from_secs(2));
+    assert_eq!(config.timing.join_retry, Duration::from_millis(500));
+    assert_eq!(config.timing.node_timeout, Duration::from_secs(45));
+    assert_eq!(config.timing.leave_timeout, Duration::from_secs(600));
+    assert_eq!(config.timing.master_timeout, TimingConfig::default().master_timeout);
+    assert!(BootstrapConfig::parse("[timing]\nnode_timeout = \"soon\"\n").is_err());
+    assert!(BootstrapConfig::parse("[timing]\nnode_timout = \"5s\"\n").is_err());
+}
+
+#[test]
+fn rejects_durations_too_long_to_represent() {
+    assert_eq!(parse_duration(&format!("{}m", u64::MAX / 60)).unwrap(), Duration::from_secs(u64::MAX / 60 * 60));
+    let err = parse_duration(&format!("{}m", u64::MAX / 60 + 1)).unwrap_err();
+    assert!(err.to_string().contains("too long"), "{}", err);
+    let err = TimingConfig::default()
+        .apply_env(|name| (name == "LEAVE_TIMEOUT").then(|| format!("{}m", u64::MAX)))
+        .unwrap_err();
+    assert!(err.to_string().contains("LEAVE_TIMEOUT"), "{}", err);
+}
+
+#[test]
+fn overrides_timing_from_environment() {
+    let mut timing = TimingConfig::default();
+    let set = timing
+        .apply_env(|name| match name {
+            "NODE_TIMEOUT" => Some(String::from("60")),
+            "ANNOUNCE_INTERVAL" => Some(String::from("250ms")),
+            _ => None,
+        })
+        .unwrap();
+    assert_eq!(set, vec!["NODE_TIMEOUT", "ANNOUNCE_INTERVAL"]);
+    assert_eq!(timing.node_timeout, Duration::from_secs(60));
+    assert_eq!(timing.announce_interval, Duration::from_millis(250));
+    let err = timing.apply_env(|name| (name == "JOIN_RETRY").then(|| String::from("1h"))).unwrap_err();
+    assert!(err.to_string().contains("JOIN_RETRY"), "{}", err);
+}
+
+#[test]
+fn rejects_unworkable_timing() {
+    assert!(TimingConfig::default().validate().is_ok());
+    let err = BootstrapConfig::parse("[timing]\njoin_retry = 0\n").unwrap_err();
+    assert!(err.to_string().contains("join_retry"), "{}", err);
+    let err = BootstrapConfig::parse("[timing]\nmaster_timeout = \"1s\"\n").unwrap_err();
+    assert!(err.to_string().contains("announce_interval"), "{}", err);
+    let err = BootstrapConfig::parse("[timing]\ndiscovery_wait = \"1s\"\n").unwrap_err();
+    assert!(err.to_string().contains("discovery_wait"), "{}", err);
+    assert!(BootstrapConfig::parse("[timing]\nnode_timeout = \"1s\"\n").is_err());
+}
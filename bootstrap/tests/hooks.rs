@@ -0,0 +1,131 @@
+use homesec_bootstrap::*;
+use std::cell::RefCell;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Hook scripts, all written before any is run so that none is still
+/// open for writing in a child forked by another test when it runs.
+fn scripts() -> &'static PathBuf {
+    static DIR: OnceLock<PathBuf> = OnceLock::new();
+    DIR.get_or_init(|| {
+        let dir = std::env::temp_dir().join(format!("homesec-hooks-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let recorded = dir.join("recorded");
+        for (name, body) in [
+            ("record", format!("{{ env | grep ^HOMESEC_ | sort; cat; }} > {}", recorded.display())),
+            ("fail", String::from("exit 3")),
+            ("hang", String::from("sleep 10")),
+        ] {
+            let path = dir.join(name);
+            std::fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        dir
+    })
+}
+
+fn script(name: &str) -> Option<String> {
+    Some(scripts().join(name).to_string_lossy().into_owned())
+}
+
+fn event(kind: HookKind) -> HookEvent {
+    HookEvent {
+        event: kind,
+        hid: Uuid::from_u128(1),
+        role: Some(Role::Agent),
+        master_hid: Some(Uuid::from_u128(2)),
+        master_addr: Some("192.168.0.10:43000".parse().unwrap()),
+        term: 3,
+    }
+}
+
+#[test]
+fn passes_the_event_in_the_environment_and_on_stdin() {
+    let hooks = ExecHooks::new(HooksConfig {
+        on_joined_as_agent: script("record"),
+        ..Default::default()
+    });
+    let event = event(HookKind::JoinedAsAgent);
+    hooks.on_joined_as_agent(&event).unwrap();
+    let record = std::fs::read_to_string(scripts().join("recorded")).unwrap();
+    let mut lines = record.lines();
+    let env: Vec<&str> = lines.by_ref().take(6).collect();
+    assert_eq!(env, [
+        "HOMESEC_EVENT=on_joined_as_agent",
+        "HOMESEC_HID=00000000-0000-0000-0000-000000000001",
+        "HOMESEC_MASTER_ADDR=192.168.0.10:43000",
+        "HOMESEC_MASTER_HID=00000000-0000-0000-0000-000000000002",
+        "HOMESEC_ROLE=agent",
+        "HOMESEC_TERM=3",
+    ]);
+    let stdin: serde_json::Value = serde_json::from_str(lines.next().unwrap()).unwrap();
+    assert_eq!(stdin["event"], "joined-as-agent");
+    assert_eq!(stdin["role"], "agent");
+    assert_eq!(stdin["master_addr"], "192.168.0.10:43000");
+    assert_eq!(stdin, serde_json::to_value(&event).unwrap());
+}
+
+#[test]
+fn reports_failed_and_hung_hooks() {
+    let hooks = ExecHooks::new(HooksConfig {
+        on_master_lost: script("fail"),
+        on_removed: script("hang"),
+        timeout: Duration::from_millis(200),
+        ..Default::default()
+    });
+    let err = hooks.on_master_lost(&event(HookKind::MasterLost)).unwrap_err();
+    assert!(err.to_string().contains("exit status: 3"), "{}", err);
+    let err = hooks.on_removed(&event(HookKind::Removed)).unwrap_err();
+    assert!(err.to_string().contains("killed"), "{}", err);
+    let err = ExecHooks::new(HooksConfig {
+        on_elected_master: script("missing"),
+        ..Default::default()
+    })
+    .on_elected_master(&event(HookKind::ElectedMaster))
+    .unwrap_err();
+    assert!(err.to_string().contains("failed to run"), "{}", err);
+    // Unset hooks succeed without running anything.
+    hooks.on_elected_master(&event(HookKind::ElectedMaster)).unwrap();
+}
+
+#[derive(Default)]
+struct Removals(RefCell<Vec<Uuid>>);
+
+impl Hooks for Removals {
+    fn on_removed(&self, event: &HookEvent) -> anyhow::Result<()> {
+        self.0.borrow_mut().push(event.hid);
+        Ok(())
+    }
+}
+
+#[test]
+fn dispatches_each_event_to_its_hook() {
+    let hooks = Removals::default();
+    fire_hook(&hooks, &event(HookKind::ElectedMaster));
+    fire_hook(&hooks, &event(HookKind::JoinedAsAgent));
+    fire_hook(&hooks, &event(HookKind::JoinedAsServer));
+    fire_hook(&hooks, &event(HookKind::MasterLost));
+    assert!(hooks.0.borrow().is_empty());
+    fire_hook(&hooks, &event(HookKind::Removed));
+    assert_eq!(*hooks.0.borrow(), [Uuid::from_u128(1)]);
+}
+
+#[test]
+fn parses_hooks_config() {
+    assert_eq!(BootstrapConfig::default().hooks, HooksConfig::default());
+    let config = BootstrapConfig::parse("[hooks]\non_removed = \"/etc/homesec/hooks/forget\"\ntimeout = \"5s\"\n").unwrap();
+    assert_eq!(config.hooks.on_removed.as_deref(), Some("/etc/homesec/hooks/forget"));
+    assert_eq!(config.hooks.on_elected_master, None);
+    assert_eq!(config.hooks.timeout, Duration::from_secs(5));
+    let config = BootstrapConfig::parse("[hooks]\non_joined_as_server = \"/etc/homesec/hooks/set-hostname\"\n").unwrap();
+    assert_eq!(config.hooks.on_joined_as_server.as_deref(), Some("/etc/homesec/hooks/set-hostname"));
+    assert_eq!(config.hooks.on_joined_as_agent, None);
+    assert!(BootstrapConfig::parse("[hooks]\non_elected = \"/bin/true\"\n").is_err());
+    let err = BootstrapConfig::parse("[hooks]\ntimeout = \"15s\"\n").unwrap_err();
+    assert!(err.to_string().contains("master_timeout"), "{}", err);
+    assert!(BootstrapConfig::parse("[hooks]\ntimeout = 0\n").is_err());
+}
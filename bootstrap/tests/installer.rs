@@ -0,0 +1,144 @@
+use homesec_bootstrap::*;
+use std::collections::BTreeMap;
+use std::net::Ipv6Addr;
+
+fn labels() -> BTreeMap<String, String> {
+    vec![("homesec/storage".to_string(), "ssd".to_string())].into_iter().collect()
+}
+
+fn k3s() -> K3s {
+    K3s {
+        node_name: "pi-1".to_string(),
+        labels: labels(),
+        server_flags: K3s::DEFAULT_SERVER_FLAGS.iter().map(|s| s.to_string()).collect(),
+        agent_flags: Vec::new(),
+        artifacts: None,
+    }
+}
+
+#[test]
+fn builds_k3s_server_args_from_config() {
+    let args = k3s().server_args(None);
+    assert_eq!(args[..2], ["server", "--cluster-init"]);
+    assert!(args.ends_with(&["--node-label".to_string(), "homesec/storage=ssd".to_string()]));
+    assert!(!args.iter().any(|arg| arg.contains("PodSecurityPolicy")));
+    let join = Join {
+        server: "192.168.1.20".parse().unwrap(),
+        token: "secret",
+    };
+    let args = k3s().server_args(Some(&join));
+    assert_eq!(args[..3], ["server", "--server", "https://192.168.1.20:6443"]);
+    assert!(!args.iter().any(|arg| arg.contains("secret")), "token leaked onto the command line");
+    assert_eq!(k3s().agent_args(), ["agent", "--node-label", "homesec/storage=ssd"]);
+}
+
+#[test]
+fn brackets_ipv6_join_urls() {
+    let join = Join {
+        server: Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x20).into(),
+        token: "secret",
+    };
+    assert_eq!(join.url(), "https://[2001:db8::20]:6443");
+}
+
+#[test]
+fn builds_k0s_args_from_config() {
+    let k0s = K0s {
+        node_name: "pi-1".to_string(),
+        labels: labels(),
+        server_flags: K0s::DEFAULT_SERVER_FLAGS.iter().map(|s| s.to_string()).collect(),
+        agent_flags: Vec::new(),
+    };
+    assert_eq!(k0s.server_args(None), [
+        "install",
+        "controller",
+        "--enable-worker",
+        "--kubelet-extra-args=--hostname-override=pi-1",
+        "--labels=homesec/storage=ssd",
+    ]);
+    let join = Join {
+        server: "192.168.1.20".parse().unwrap(),
+        token: "secret",
+    };
+    assert_eq!(k0s.agent_args(&join).last().unwrap(), &format!("--token-file={}", K0s::TOKEN_PATH));
+}
+
+#[test]
+fn parses_installer_config() {
+    let config = BootstrapConfig::parse(
+        r#"
+        [installer]
+        distribution = "k0s"
+        agent_flags = ["--debug"]
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.installer.distribution, Distribution::K0s);
+    assert_eq!(config.installer.server_flags, None);
+    assert_eq!(config.installer.agent_flags, Some(vec!["--debug".to_string()]));
+    assert_eq!(BootstrapConfig::default().installer.distribution, Distribution::K3s);
+}
+
+#[test]
+fn external_installs_nothing() {
+    let installer = new_installer(
+        &InstallerConfig {
+            distribution: Distribution::External,
+            ..Default::default()
+        },
+        "pi-1".to_string(),
+        labels(),
+        None,
+    );
+    assert!(installer.is_installed());
+    installer.install_server(None).unwrap();
+    assert_eq!(installer.join_token(true).unwrap(), "");
+}
+
+#[test]
+fn stub_records_calls() {
+    let path = std::env::temp_dir().join(format!("homesec-stub-{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let installer = new_installer(
+        &InstallerConfig {
+            distribution: Distribution::Stub,
+            record_path: Some(path.to_string_lossy().into_owned()),
+            ..Default::default()
+        },
+        "pi-1".to_string(),
+        labels(),
+        None,
+    );
+    assert!(!installer.is_installed());
+    let token = installer.join_token(false).unwrap();
+    assert_eq!(token, "pi-1:agent");
+    let join = Join {
+        server: "192.168.1.20".parse().unwrap(),
+        token: &token,
+    };
+    installer.install_agent(&join).unwrap();
+    assert!(installer.is_installed());
+    installer.uninstall().unwrap();
+    assert!(!installer.is_installed());
+    assert_eq!(read_stub_calls(&path).unwrap(), vec![
+        StubCall::InstallAgent {
+            node: "pi-1".to_string(),
+            server: join.server,
+            token: "pi-1:agent".to_string(),
+        },
+        StubCall::Uninstall { node: "pi-1".to_string() },
+    ]);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn writes_secrets_readable_by_owner_only() {
+    use std::os::unix::fs::PermissionsExt;
+    let path = std::env::temp_dir().join(format!("homesec-secret-{}", std::process::id()));
+    std::fs::write(&path, "old").unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+    write_secret(&path, "K10::server:secret").unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "K10::server:secret");
+    assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    std::fs::remove_file(&path).unwrap();
+}
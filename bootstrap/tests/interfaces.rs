@@ -0,0 +1,42 @@
+use homesec_bootstrap::*;
+use std::net::Ipv4Addr;
+
+fn iface(name: &str, addr: [u8; 4], netmask: [u8; 4]) -> Interface {
+    Interface {
+        name: name.to_string(),
+        addr: addr.into(),
+        netmask: netmask.into(),
+    }
+}
+
+#[test]
+fn computes_directed_broadcast_for_any_prefix() {
+    assert_eq!(iface("eth0", [192, 168, 1, 20], [255, 255, 255, 0]).broadcast(), Ipv4Addr::new(192, 168, 1, 255));
+    assert_eq!(iface("eth0", [10, 1, 2, 3], [255, 255, 0, 0]).broadcast(), Ipv4Addr::new(10, 1, 255, 255));
+    assert_eq!(iface("eth0", [172, 16, 5, 9], [255, 255, 240, 0]).broadcast(), Ipv4Addr::new(172, 16, 15, 255));
+    assert_eq!(iface("eth0", [192, 168, 1, 129], [255, 255, 255, 192]).broadcast(), Ipv4Addr::new(192, 168, 1, 191));
+}
+
+#[test]
+fn selects_first_interface_by_default() {
+    let available = vec![
+        iface("eth0", [192, 168, 1, 20], [255, 255, 255, 0]),
+        iface("wlan0", [10, 0, 0, 5], [255, 0, 0, 0]),
+    ];
+    assert_eq!(select_interfaces(&available, &[]).unwrap(), vec![available[0].clone()]);
+    assert!(select_interfaces(&[], &[]).is_err());
+}
+
+#[test]
+fn selects_every_address_on_named_interfaces() {
+    let available = vec![
+        iface("eth0", [192, 168, 1, 20], [255, 255, 255, 0]),
+        iface("wlan0", [10, 0, 0, 5], [255, 0, 0, 0]),
+        iface("wlan0", [172, 16, 0, 5], [255, 255, 0, 0]),
+    ];
+    let selected = select_interfaces(&available, &["wlan0".to_string()]).unwrap();
+    assert_eq!(selected, available[1..].to_vec());
+    let selected = select_interfaces(&available, &["wlan0".to_string(), "eth0".to_string()]).unwrap();
+    assert_eq!(selected.len(), 3);
+    assert!(select_interfaces(&available, &["eth1".to_string()]).is_err());
+}
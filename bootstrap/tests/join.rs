@@ -0,0 +1,167 @@
+mod scratch;
+
+use homesec_bootstrap::*;
+use scratch::*;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use uuid::Uuid;
+
+const KEY: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+fn auth() -> Authenticator {
+    Authenticator::new(KEY.to_vec()).unwrap()
+}
+
+fn timing() -> TimingConfig {
+    TimingConfig {
+        discovery_wait: Duration::from_millis(1500),
+        announce_interval: Duration::from_millis(50),
+        join_timeout: Duration::from_secs(5),
+        join_retry: Duration::from_millis(100),
+        ..TimingConfig::default()
+    }
+}
+
+/// A node on loopback whose broadcasts all go to a sniffer, and which
+/// captures everything it sends.
+struct Node {
+    transport: Transport,
+    addr: SocketAddr,
+    sniffer: UdpSocket,
+    capture: std::path::PathBuf,
+}
+
+impl Node {
+    fn new(name: &str) -> Self {
+        let sniffer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sniffer.set_nonblocking(true).unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let transport = Transport::new(socket, vec![sniffer.local_addr().unwrap()], auth());
+        let capture = scratch("join", name);
+        transport.set_capture(CaptureWriter::open(&capture).unwrap());
+        Self {
+            transport,
+            addr,
+            sniffer,
+            capture,
+        }
+    }
+
+    fn sent(&self) -> Vec<Message> {
+        read_capture(&self.capture).unwrap().into_iter()
+            .filter(|record| record.direction == Direction::Sent)
+            .map(|record| record.message)
+            .collect()
+    }
+
+    fn broadcast_anything(&self) -> bool {
+        self.sniffer.recv_from(&mut [0; MAX_DATAGRAM_SIZE]).is_ok()
+    }
+}
+
+/// A master announcing `details` to `node` and answering its join
+/// requests with `token`, until the returned flag is set.
+fn fake_master(details: ConnectionDetails, node: SocketAddr, token: &'static str) -> (SocketAddr, Arc<AtomicBool>, JoinHandle<()>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+    let addr = socket.local_addr().unwrap();
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = stop.clone();
+    let handle = std::thread::spawn(move || {
+        let mut codec = Codec::new(auth());
+        let mut buf = [0; MAX_DATAGRAM_SIZE];
+        while !stopped.load(Ordering::SeqCst) {
+            let announcement = codec.encode(&Message::ConnectionDetails(details.clone())).unwrap();
+            socket.send_to(&announcement, node).unwrap();
+            let (n, from) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(_) => continue,
+            };
+            if let Ok(Decoded::Message(Message::JoinRequest(JoinRequest { hid }))) = codec.decode(&buf[..n]) {
+                let (nonce, sealed) = codec.auth().seal_token(hid, details.hid, token).unwrap();
+                let response = codec.encode(&Message::JoinResponse(JoinResponse {
+                    hid,
+                    master: details.hid,
+                    nonce,
+                    token: sealed,
+                })).unwrap();
+                socket.send_to(&response, from).unwrap();
+            }
+        }
+    });
+    (addr, stop, handle)
+}
+
+#[tokio::test]
+async fn joins_an_announced_master_without_an_election() {
+    let node = Node::new("announced");
+    let mut inbox = Pump::new(&node.transport).unwrap().spawn();
+    let hid = Uuid::new_v4();
+    let details = ConnectionDetails {
+        hid: Uuid::new_v4(),
+        servers: vec![],
+        artifacts: None,
+        term: 4,
+        api_addr: None,
+    };
+    let (master, stop, handle) = fake_master(details.clone(), node.addr, "K10::server:secret");
+
+    let joined = join_existing_master(&node.transport, &mut inbox, hid, 2, &timing()).await.unwrap().unwrap();
+    stop.store(true, Ordering::SeqCst);
+    handle.join().unwrap();
+
+    assert_eq!(joined.addr, master);
+    assert_eq!(joined.details, details);
+    assert_eq!(joined.token, "K10::server:secret");
+    let sent = node.sent();
+    assert!(!sent.is_empty());
+    assert!(sent.iter().all(|msg| *msg == Message::JoinRequest(JoinRequest { hid })), "{:?}", sent);
+    assert!(!node.broadcast_anything());
+}
+
+#[tokio::test]
+async fn ignores_a_master_from_an_earlier_term() {
+    let node = Node::new("earlier");
+    let mut inbox = Pump::new(&node.transport).unwrap().spawn();
+    let details = ConnectionDetails {
+        hid: Uuid::new_v4(),
+        servers: vec![],
+        artifacts: None,
+        term: 1,
+        api_addr: None,
+    };
+    let (_, stop, handle) = fake_master(details, node.addr, "K10::server:secret");
+
+    let joined = join_existing_master(&node.transport, &mut inbox, Uuid::new_v4(), 2, &timing()).await.unwrap();
+    stop.store(true, Ordering::SeqCst);
+    handle.join().unwrap();
+
+    assert!(joined.is_none());
+    assert!(node.sent().is_empty());
+    assert!(!node.broadcast_anything());
+}
+
+#[test]
+fn joins_the_announced_api_address() {
+    let joined = |source: &str, api_addr: Option<&str>| Joined {
+        addr: source.parse().unwrap(),
+        details: ConnectionDetails {
+            hid: Uuid::from_u128(1),
+            servers: vec![],
+            artifacts: None,
+            term: 1,
+            api_addr: api_addr.map(|addr| addr.parse().unwrap()),
+        },
+        token: String::new(),
+    };
+    let server = |source, api_addr| joined(source, api_addr).api_server().map(|ip| ip.to_string());
+    assert_eq!(server("192.168.1.20:43000", None).unwrap(), "192.168.1.20");
+    assert_eq!(server("[fe80::1%2]:43000", Some("2001:db8::20")).unwrap(), "2001:db8::20");
+    let err = server("[fe80::1%2]:43000", None).unwrap_err();
+    assert!(err.to_string().contains("no routable API address"), "{}", err);
+}
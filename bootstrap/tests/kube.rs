@@ -0,0 +1,35 @@
+use homesec_bootstrap::*;
+
+const NODES: &str = r#"{
+    "apiVersion": "v1",
+    "kind": "List",
+    "items": [
+        {"metadata": {"name": "pi-a", "labels": {"node-role.kubernetes.io/control-plane": "true", "node-role.kubernetes.io/master": "true"}}},
+        {"metadata": {"name": "pi-b", "labels": {"kubernetes.io/arch": "arm64"}}},
+        {"metadata": {"name": "pi-c"}}
+    ]
+}"#;
+
+fn node(name: &str, control_plane: bool) -> ClusterNode {
+    ClusterNode {
+        name: name.to_string(),
+        control_plane,
+    }
+}
+
+#[test]
+fn parses_node_roles() {
+    assert_eq!(parse_nodes(NODES).unwrap(), vec![node("pi-a", true), node("pi-b", false), node("pi-c", false)]);
+    assert!(parse_nodes("{}").is_err());
+}
+
+#[test]
+fn detects_orphaned_agents() {
+    let nodes = parse_nodes(NODES).unwrap();
+    assert!(orphans_agents(&nodes, "pi-a"));
+    assert!(!orphans_agents(&nodes, "pi-b"));
+    // A single node cluster has nobody to orphan.
+    assert!(!orphans_agents(&[node("pi-a", true)], "pi-a"));
+    // Another server takes over.
+    assert!(!orphans_agents(&[node("pi-a", true), node("pi-b", false), node("pi-d", true)], "pi-a"));
+}
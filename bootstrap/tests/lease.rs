@@ -0,0 +1,111 @@
+mod sim;
+
+use proptest::prelude::*;
+use sim::*;
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+/// A bus and a partition into two groups, split at `split`.
+#[derive(Clone, Debug)]
+struct Scenario {
+    count: usize,
+    seed: u64,
+    loss: f64,
+    max_delay: u64,
+    split: usize,
+    from: u64,
+    len: u64,
+}
+
+impl Scenario {
+    fn simulation(&self) -> Simulation {
+        let split = self.split.min(self.count - 1);
+        Simulation::new(
+            self.count,
+            BusConfig {
+                loss: self.loss,
+                max_delay: self.max_delay,
+            },
+            self.seed,
+        )
+        .partition(Partition {
+            groups: vec![(0..split).collect(), (split..self.count).collect()],
+            from: self.from,
+            until: self.from + self.len,
+        })
+    }
+}
+
+/// Partitions start once the nodes have heard each other. `max_len`
+/// bounds how long they last.
+fn scenario(max_len: u64) -> impl Strategy<Value = Scenario> {
+    (3usize..8, any::<u64>(), 0.0..0.3f64, 0u64..3, 1usize..7, 10u64..60, 1..max_len).prop_map(
+        |(count, seed, loss, max_delay, split, from, len)| Scenario {
+            count,
+            seed,
+            loss,
+            max_delay,
+            split,
+            from,
+            len,
+        },
+    )
+}
+
+fn terms(sim: &Simulation) -> Vec<u64> {
+    sim.nodes.iter().map(|n| n.election.term).collect()
+}
+
+/// Runs `sim` for 300 ticks, checking that terms only ever move forward
+/// and that no term has two masters.
+fn check_one_master_per_term(mut sim: Simulation) -> Result<(), TestCaseError> {
+    for _ in 0..300 {
+        let before = terms(&sim);
+        sim.step();
+        for (i, (before, after)) in before.iter().zip(terms(&sim)).enumerate() {
+            prop_assert!(after >= *before, "node {} went back from term {} to {}", i, before, after);
+        }
+    }
+    let mut masters = BTreeMap::<u64, Uuid>::new();
+    for (term, master) in &sim.results {
+        let elected = *masters.entry(*term).or_insert(*master);
+        prop_assert_eq!(elected, *master, "two masters in term {}", term);
+    }
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    /// While a partition is shorter than the node timeout nobody forgets a
+    /// peer, so every quorum in a term overlaps and at most one master can
+    /// win it. Terms only ever move forward.
+    #[test]
+    fn never_elects_two_masters_in_one_term(scenario in scenario(25)) {
+        check_one_master_per_term(scenario.simulation())?;
+    }
+
+    /// With the cluster size configured only a majority of the cluster
+    /// elects a master, so partitions may outlast the node timeout.
+    #[test]
+    fn never_elects_two_masters_in_a_sized_cluster(scenario in scenario(90)) {
+        check_one_master_per_term(scenario.simulation().cluster_size(scenario.count))?;
+    }
+
+    /// However long a partition lasts, once it heals every node follows
+    /// the same master, in the latest term anyone concluded.
+    #[test]
+    fn converges_on_the_highest_term_leader(scenario in scenario(90)) {
+        let mut sim = scenario.simulation();
+        let healed = scenario.from + scenario.len;
+        while sim.tick < healed + 250 {
+            sim.step();
+        }
+        prop_assert!(sim.nodes.iter().all(|n| n.outcome.is_some()), "not every node concluded");
+        let master = sim.assert_agreement();
+        let term = sim.nodes[0].outcome_term;
+        prop_assert!(sim.nodes.iter().all(|n| n.outcome_term == term), "nodes follow {} in different terms", master);
+        let latest = sim.results.iter().map(|(term, _)| *term).max().unwrap();
+        prop_assert_eq!(term, latest);
+    }
+}
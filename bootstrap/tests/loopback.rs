@@ -0,0 +1,243 @@
+//! Runs whole daemons against each other on loopback, each on its own
+//! port and broadcasting to every other one, with the stub installer
+//! standing in for k3s. The same checks as the test against real Pis
+//! apply.
+
+use homesec_bootstrap::*;
+use std::net::{SocketAddr, UdpSocket};
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+const KEY: &str = "0123456789abcdef0123456789abcdef";
+
+const CONFIG: &str = r#"
+mdns = false
+
+[installer]
+distribution = "stub"
+record_path = "calls.jsonl"
+
+[timing]
+discovery_wait = "500ms"
+election_delay = "1s"
+election_interval = "100ms"
+node_timeout = "5s"
+master_timeout = "5s"
+announce_interval = "200ms"
+join_retry = "200ms"
+
+[hooks]
+on_elected_master = "../hook"
+on_joined_as_agent = "../hook"
+on_joined_as_server = "../hook"
+timeout = "2s"
+"#;
+
+/// Appends each event to the node's `events`.
+const HOOK: &str = "#!/bin/sh\necho \"$HOMESEC_EVENT $HOMESEC_ROLE $HOMESEC_MASTER_HID $HOMESEC_MASTER_ADDR\" >> events\n";
+
+fn free_port() -> u16 {
+    UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// A running daemon, killed when dropped. Its files, including its
+/// output in `log`, are kept in `dir`.
+struct Daemon {
+    dir: PathBuf,
+    addr: SocketAddr,
+    child: Child,
+}
+
+impl Daemon {
+    fn hid(&self) -> Uuid {
+        std::fs::read_to_string(self.dir.join("hid")).unwrap().trim().parse().unwrap()
+    }
+
+    fn node(&self) -> String {
+        format!("pi-{}", self.hid())
+    }
+
+    fn calls(&self) -> Vec<StubCall> {
+        read_stub_calls(self.dir.join("calls.jsonl")).unwrap()
+    }
+
+    /// Events its hook ran for.
+    fn events(&self) -> Vec<String> {
+        std::fs::read_to_string(self.dir.join("events")).unwrap_or_default().lines().map(String::from).collect()
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Daemons sharing a key, each of which broadcasts to the ports of all
+/// the others and to an observer.
+struct Cluster {
+    dir: PathBuf,
+    ports: Vec<u16>,
+    observer: UdpSocket,
+    codec: Codec,
+    daemons: Vec<Daemon>,
+}
+
+impl Cluster {
+    /// Reserves ports for `count` daemons without starting them.
+    fn new(name: &str, count: usize) -> Self {
+        let dir = std::env::temp_dir().join(format!("homesec-loopback-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("key"), KEY).unwrap();
+        std::fs::write(dir.join("hook"), HOOK).unwrap();
+        std::fs::set_permissions(dir.join("hook"), std::fs::Permissions::from_mode(0o755)).unwrap();
+        let observer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        observer.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        Self {
+            dir,
+            ports: (0..count).map(|_| free_port()).collect(),
+            observer,
+            codec: Codec::new(Authenticator::new(KEY.as_bytes().to_vec()).unwrap()),
+            daemons: Vec::new(),
+        }
+    }
+
+    /// Starts the daemon on the `i`th port with `server_count` set to
+    /// `servers`.
+    fn start(&mut self, i: usize, servers: usize) -> SocketAddr {
+        let dir = self.dir.join(format!("node-{}", i));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("bootstrap.toml"), format!("server_count = {}\n{}", servers, CONFIG)).unwrap();
+        let targets: Vec<String> = self.ports.iter()
+            .map(|port| format!("127.0.0.1:{}", port))
+            .chain(std::iter::once(self.observer.local_addr().unwrap().to_string()))
+            .collect();
+        let child = Command::new(env!("CARGO_BIN_EXE_homesec_bootstrap"))
+            .arg("daemon")
+            .current_dir(&dir)
+            .env("PORT", self.ports[i].to_string())
+            .env("BROADCAST_ADDR", targets.join(","))
+            .env("CLUSTER_KEY_PATH", self.dir.join("key"))
+            .env("BOOTSTRAP_CONFIG", dir.join("bootstrap.toml"))
+            .env("BOOTSTRAP_STATE_PATH", dir.join("state.json"))
+            .env("BOOTSTRAP_HID_PATH", dir.join("hid"))
+            .stdout(Stdio::from(std::fs::File::create(dir.join("log")).unwrap()))
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let addr = SocketAddr::from(([127, 0, 0, 1], self.ports[i]));
+        self.daemons.push(Daemon { dir, addr, child });
+        addr
+    }
+
+    /// Feeds broadcasts to `observer` until `done` holds, failing after
+    /// `timeout`.
+    fn observe_until(&mut self, observer: &mut ElectionObserver, timeout: Duration, done: impl Fn(&ElectionObserver, &[Daemon]) -> bool) {
+        let deadline = Instant::now() + timeout;
+        let mut buf = [0; MAX_DATAGRAM_SIZE];
+        while !done(observer, &self.daemons) {
+            assert!(Instant::now() < deadline, "timed out after {:?}; daemon logs are in {}", timeout, self.dir.display());
+            let (n, addr) = match self.observer.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(_) => continue,
+            };
+            if let Ok(Decoded::Message(msg)) = self.codec.decode(&buf[..n]) {
+                observer.observe(addr, &msg).unwrap();
+            }
+        }
+    }
+
+    fn daemon(&self, addr: SocketAddr) -> &Daemon {
+        self.daemons.iter().find(|daemon| daemon.addr == addr).unwrap()
+    }
+}
+
+/// Keeps the daemons' files if the test failed.
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        self.daemons.clear();
+        if !std::thread::panicking() {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+/// Whether every daemon installed and ran its hook.
+fn installed(daemons: &[Daemon]) -> bool {
+    daemons.iter().all(|daemon| !daemon.calls().is_empty() && !daemon.events().is_empty())
+}
+
+fn join_server(daemon: &Daemon, master: Uuid) -> StubCall {
+    StubCall::InstallServer {
+        node: daemon.node(),
+        server: Some("127.0.0.1".parse().unwrap()),
+        token: Some(format!("pi-{}:server", master)),
+    }
+}
+
+fn join_agent(daemon: &Daemon, master: Uuid) -> StubCall {
+    StubCall::InstallAgent {
+        node: daemon.node(),
+        server: "127.0.0.1".parse().unwrap(),
+        token: format!("pi-{}:agent", master),
+    }
+}
+
+#[test]
+fn elects_one_master_and_installs_every_node() {
+    let count = 4;
+    let mut cluster = Cluster::new("elect", count);
+    for i in 0..count {
+        cluster.start(i, 3);
+    }
+    let mut observer = ElectionObserver::new();
+    cluster.observe_until(&mut observer, Duration::from_secs(30), |observer, daemons| {
+        observer.concluded(count) && installed(daemons)
+    });
+    let master = observer.check(count).unwrap();
+    let master_hid = cluster.daemon(master).hid();
+    let mut servers = 0;
+    let mut agents = 0;
+    for daemon in &cluster.daemons {
+        let calls = daemon.calls();
+        if daemon.addr == master {
+            assert_eq!(calls, vec![StubCall::InstallServer { node: daemon.node(), server: None, token: None }]);
+            assert_eq!(daemon.events(), [format!("on_elected_master master {} {}", master_hid, master)]);
+        } else if calls == vec![join_server(daemon, master_hid)] {
+            assert_eq!(daemon.events(), [format!("on_joined_as_server server {} {}", master_hid, master)]);
+            servers += 1;
+        } else {
+            assert_eq!(calls, vec![join_agent(daemon, master_hid)]);
+            assert_eq!(daemon.events(), [format!("on_joined_as_agent agent {} {}", master_hid, master)]);
+            agents += 1;
+        }
+    }
+    assert_eq!((servers, agents), (2, 1));
+}
+
+#[test]
+fn late_node_joins_without_an_election() {
+    let mut cluster = Cluster::new("late", 4);
+    for i in 0..3 {
+        cluster.start(i, 3);
+    }
+    let mut observer = ElectionObserver::new();
+    cluster.observe_until(&mut observer, Duration::from_secs(30), |observer, daemons| {
+        observer.concluded(3) && installed(daemons)
+    });
+    let master = observer.check(3).unwrap();
+    let late = cluster.start(3, 3);
+    cluster.observe_until(&mut observer, Duration::from_secs(30), |_, daemons| installed(daemons));
+    assert_eq!(observer.check(3).unwrap(), master);
+    assert!(!observer.appearances.contains(&late), "{} appeared for an election", late);
+    assert!(!observer.votes.values().any(|voters| voters.contains(&late)), "{} voted", late);
+    assert!(!observer.results.contains_key(&late));
+    let master_hid = cluster.daemon(master).hid();
+    let late = cluster.daemon(late);
+    assert_eq!(late.calls(), vec![join_agent(late, master_hid)]);
+}
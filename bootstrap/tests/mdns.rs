@@ -0,0 +1,30 @@
+use homesec_bootstrap::*;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use uuid::Uuid;
+
+#[test]
+fn parses_advertised_master() {
+    let hid = Uuid::from_u128(42);
+    let addrs: Vec<IpAddr> = vec![Ipv6Addr::LOCALHOST.into(), Ipv4Addr::new(192, 168, 1, 20).into()];
+    let record = parse_master(&txt_properties(hid, "master", 43000), addrs.clone(), API_PORT).unwrap();
+    assert_eq!(record, MasterRecord {
+        hid,
+        addrs,
+        api_port: API_PORT,
+        bootstrap_port: 43000,
+    });
+    assert_eq!(record.bootstrap_addr(), Some("192.168.1.20:43000".parse::<SocketAddr>().unwrap()));
+}
+
+#[test]
+fn ignores_incomplete_or_non_master_records() {
+    let hid = Uuid::from_u128(42);
+    let addrs: Vec<IpAddr> = vec![Ipv4Addr::new(192, 168, 1, 20).into()];
+    assert!(parse_master(&txt_properties(hid, "agent", 43000), addrs.clone(), API_PORT).is_none());
+    assert!(parse_master(&txt_properties(hid, "master", 43000), Vec::new(), API_PORT).is_none());
+    let mut properties = txt_properties(hid, "master", 43000);
+    properties.insert("hid".to_string(), "not-a-uuid".to_string());
+    assert!(parse_master(&properties, addrs.clone(), API_PORT).is_none());
+    properties.remove("hid");
+    assert!(parse_master(&properties, addrs, API_PORT).is_none());
+}
@@ -0,0 +1,71 @@
+use homesec_bootstrap::*;
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+fn addr(i: u8) -> SocketAddr {
+    SocketAddr::from(([10, 0, 0, i], 43000))
+}
+
+fn details(term: u64) -> Message {
+    Message::ConnectionDetails(ConnectionDetails {
+        hid: Uuid::new_v4(),
+        servers: vec![],
+        artifacts: None,
+        term,
+        api_addr: None,
+    })
+}
+
+/// Every node appears, votes for node 1 and concludes in `term`.
+fn elect(observer: &mut ElectionObserver, term: u64) {
+    for i in 1..=3 {
+        let appearance = Message::Appearance(AppearanceMessage {
+            priority: i as i32,
+            is_master: false,
+            hid: Uuid::new_v4(),
+            static_priority: 0,
+            master_eligible: true,
+            labels: Default::default(),
+            term,
+            role: None,
+        });
+        observer.observe(addr(i), &appearance).unwrap();
+        let hid = Uuid::nil();
+        observer.observe(addr(i), &Message::CastVote(CastVote { addr: addr(1), hid, term })).unwrap();
+        observer.observe(addr(i), &Message::ElectionResult(ElectionResult { addr: addr(1), hid, term })).unwrap();
+    }
+}
+
+#[test]
+fn accepts_a_unanimous_election() {
+    let mut observer = ElectionObserver::new();
+    elect(&mut observer, 2);
+    assert!(!observer.concluded(3));
+    observer.observe(addr(1), &details(2)).unwrap();
+    observer.observe(addr(1), &details(2)).unwrap();
+    assert!(observer.concluded(3));
+    assert_eq!(observer.check(3).unwrap(), addr(1));
+    let err = observer.check(4).unwrap_err();
+    assert!(err.to_string().contains("3/4"), "{}", err);
+}
+
+#[test]
+fn rejects_two_masters_in_one_term() {
+    let mut observer = ElectionObserver::new();
+    elect(&mut observer, 2);
+    observer.observe(addr(1), &details(2)).unwrap();
+    // An earlier term's master that has not yet stood down is tolerated.
+    observer.observe(addr(2), &details(1)).unwrap();
+    assert!(observer.observe(addr(2), &details(2)).is_err());
+    assert!(observer.observe(addr(2), &Message::Reset).is_err());
+}
+
+#[test]
+fn rejects_a_split_result() {
+    let mut observer = ElectionObserver::new();
+    elect(&mut observer, 0);
+    observer.observe(addr(1), &details(0)).unwrap();
+    observer.observe(addr(3), &Message::ElectionResult(ElectionResult { addr: addr(3), hid: Uuid::nil(), term: 0 })).unwrap();
+    let err = observer.check(3).unwrap_err();
+    assert!(err.to_string().contains("disagree"), "{}", err);
+}
@@ -0,0 +1,104 @@
+use homesec_bootstrap::*;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+fn appearance(hid: Uuid, priority: i32) -> Message {
+    Message::Appearance(AppearanceMessage {
+        priority,
+        is_master: false,
+        hid,
+        static_priority: 5,
+        master_eligible: true,
+        labels: BTreeMap::new(),
+        term: 0,
+        role: None,
+    })
+}
+
+#[test]
+fn tallies_the_latest_vote_from_each_node() {
+    let a: SocketAddr = "10.0.0.1:43000".parse().unwrap();
+    let b: SocketAddr = "10.0.0.2:43000".parse().unwrap();
+    let (hid_a, hid_b) = (Uuid::new_v4(), Uuid::new_v4());
+    let now = SystemTime::now();
+    let mut table = PeerTable::new();
+    table.observe(a, &appearance(hid_a, 1), now);
+    table.observe(b, &appearance(hid_b, 2), now);
+    table.observe(a, &Message::CastVote(CastVote { addr: a, hid: hid_a, term: 0 }), now);
+    table.observe(b, &Message::CastVote(CastVote { addr: a, hid: hid_a, term: 0 }), now);
+    table.observe(a, &Message::CastVote(CastVote { addr: b, hid: hid_b, term: 0 }), now);
+    let peers = table.peers();
+    assert_eq!(peers.len(), 2);
+    let votes = |hid| peers.iter().find(|peer| peer.hid == hid).unwrap().votes;
+    assert_eq!(votes(hid_a), 1);
+    assert_eq!(votes(hid_b), 1);
+}
+
+#[test]
+fn tracks_masters_and_nodes_without_appearances() {
+    let master: SocketAddr = "10.0.0.1:43000".parse().unwrap();
+    let agent: SocketAddr = "10.0.0.2:43000".parse().unwrap();
+    let (master_hid, agent_hid) = (Uuid::new_v4(), Uuid::new_v4());
+    let start = SystemTime::now();
+    let mut table = PeerTable::new();
+    table.observe(master, &appearance(master_hid, 7), start);
+    table.observe(master, &Message::ConnectionDetails(ConnectionDetails {
+        hid: master_hid,
+        servers: vec![master_hid],
+        artifacts: None,
+        term: 0,
+        api_addr: None,
+    }), start);
+    table.observe(agent, &Message::JoinRequest(JoinRequest { hid: agent_hid }), start);
+    let later = start + Duration::from_secs(5);
+    table.observe(master, &Message::Reset, later);
+
+    let peers = table.peers();
+    let master_peer = peers.iter().find(|peer| peer.hid == master_hid).unwrap();
+    assert!(master_peer.is_master);
+    assert_eq!(master_peer.priority, Some(7));
+    assert_eq!(master_peer.last_seen, later);
+    let agent_peer = peers.iter().find(|peer| peer.hid == agent_hid).unwrap();
+    assert!(!agent_peer.is_master);
+    assert_eq!(agent_peer.priority, None);
+    assert_eq!(agent_peer.addr, agent);
+    assert_eq!(agent_peer.last_seen, start);
+}
+
+#[test]
+fn forgets_peers_that_leave() {
+    let a: SocketAddr = "10.0.0.1:43000".parse().unwrap();
+    let b: SocketAddr = "10.0.0.2:43000".parse().unwrap();
+    let (hid_a, hid_b) = (Uuid::new_v4(), Uuid::new_v4());
+    let now = SystemTime::now();
+    let mut table = PeerTable::new();
+    table.observe(a, &appearance(hid_a, 1), now);
+    table.observe(b, &appearance(hid_b, 2), now);
+    table.observe(b, &Message::CastVote(CastVote { addr: a, hid: hid_a, term: 0 }), now);
+    table.observe(b, &Message::Leave(LeaveMessage { hid: hid_b }), now);
+    let peers = table.peers();
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peers[0].hid, hid_a);
+    assert_eq!(peers[0].votes, 0);
+}
+
+#[test]
+fn tallies_only_the_latest_term() {
+    let a: SocketAddr = "10.0.0.1:43000".parse().unwrap();
+    let b: SocketAddr = "10.0.0.2:43000".parse().unwrap();
+    let (hid_a, hid_b) = (Uuid::new_v4(), Uuid::new_v4());
+    let now = SystemTime::now();
+    let mut table = PeerTable::new();
+    table.observe(a, &appearance(hid_a, 1), now);
+    table.observe(b, &appearance(hid_b, 2), now);
+    table.observe(a, &Message::CastVote(CastVote { addr: a, hid: hid_a, term: 0 }), now);
+    table.observe(b, &Message::CastVote(CastVote { addr: b, hid: hid_b, term: 1 }), now);
+    let peers = table.peers();
+    let peer = |hid| peers.iter().find(|peer| peer.hid == hid).unwrap();
+    assert_eq!(peer(hid_a).votes, 0);
+    assert_eq!(peer(hid_b).votes, 1);
+    assert_eq!(peer(hid_a).term, 0);
+    assert_eq!(peer(hid_b).term, 1);
+}
@@ -0,0 +1,139 @@
+use homesec_bootstrap::*;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+fn received(time: SystemTime, addr: SocketAddr, message: Message) -> CaptureRecord {
+    CaptureRecord::new(time, Direction::Received, addr, message)
+}
+
+fn appearance(hid: Uuid, priority: i32) -> Message {
+    Message::Appearance(AppearanceMessage {
+        priority,
+        is_master: false,
+        hid,
+        static_priority: 0,
+        master_eligible: true,
+        labels: BTreeMap::new(),
+        term: 0,
+        role: None,
+    })
+}
+
+#[test]
+fn reproduces_votes_and_result() {
+    let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let nodes: Vec<(SocketAddr, Uuid, i32)> = (1..=3)
+        .map(|i| (format!("10.0.0.{}:43000", i).parse().unwrap(), Uuid::new_v4(), i))
+        .collect();
+    let (best_addr, best_hid, _) = nodes[2];
+    let mut records = Vec::new();
+    for second in 0..12 {
+        for (addr, hid, priority) in &nodes {
+            records.push(received(start + Duration::from_secs(second), *addr, appearance(*hid, *priority)));
+        }
+    }
+    // Sent messages must not count as heard.
+    records.push(CaptureRecord::new(start + Duration::from_secs(12), Direction::Sent, nodes[0].0, Message::Reset));
+    for (addr, _, _) in &nodes {
+        records.push(received(start + Duration::from_secs(12), *addr, Message::CastVote(CastVote {
+            addr: best_addr,
+            hid: best_hid,
+            term: 0,
+        })));
+    }
+    for second in 13..30 {
+        for (addr, hid, priority) in &nodes {
+            records.push(received(start + Duration::from_secs(second), *addr, appearance(*hid, *priority)));
+        }
+    }
+
+    let mut replay = Replay::new(start, &BootstrapConfig::default());
+    let decisions: Vec<(SystemTime, Decision)> = records.iter().flat_map(|record| replay.feed(record)).collect();
+    assert_eq!(decisions.len(), 2, "{:?}", decisions);
+    assert_eq!(decisions[0], (start + Duration::from_secs(10), Decision::Vote { addr: best_addr, hid: best_hid, term: 0 }));
+    assert_eq!(decisions[1].1, Decision::Elected { addr: best_addr, hid: best_hid, term: 0 });
+    assert!(decisions[1].0 >= start + Duration::from_secs(22));
+}
+
+#[test]
+fn moves_on_to_a_new_term_without_a_quorum() {
+    let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let nodes: Vec<(SocketAddr, Uuid, i32)> = (1..=3)
+        .map(|i| (format!("10.0.0.{}:43000", i).parse().unwrap(), Uuid::new_v4(), i))
+        .collect();
+    let (best_addr, best_hid, _) = nodes[2];
+    // Every node keeps appearing, but the votes of the others are lost.
+    let mut records = Vec::new();
+    for second in 0..40 {
+        for (addr, hid, priority) in &nodes {
+            records.push(received(start + Duration::from_secs(second), *addr, appearance(*hid, *priority)));
+        }
+    }
+    let mut replay = Replay::new(start, &BootstrapConfig::default());
+    let decisions: Vec<Decision> = records.iter().flat_map(|record| replay.feed(record)).map(|(_, decision)| decision).collect();
+    assert_eq!(decisions, vec![
+        Decision::Vote { addr: best_addr, hid: best_hid, term: 0 },
+        Decision::NewTerm(1),
+    ]);
+}
+
+#[test]
+fn starts_over_in_a_later_term() {
+    let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let master: SocketAddr = "10.0.0.1:43000".parse().unwrap();
+    let hid = Uuid::new_v4();
+    let details = |term| Message::ConnectionDetails(ConnectionDetails {
+        hid,
+        servers: vec![hid],
+        artifacts: None,
+        term,
+        api_addr: None,
+    });
+    let mut replay = Replay::new(start, &BootstrapConfig::default());
+    let first = replay.feed(&received(start, master, details(0)));
+    assert_eq!(first, vec![(start, Decision::Elected { addr: master, hid, term: 0 })]);
+    // Concluded, so further traffic in the same term decides nothing.
+    assert!(replay.feed(&received(start + Duration::from_secs(1), master, details(0))).is_empty());
+    let later = start + Duration::from_secs(2);
+    assert_eq!(replay.feed(&received(later, master, details(2))), vec![(later, Decision::Elected { addr: master, hid, term: 2 })]);
+    // Older nodes ask for a new term with a reset.
+    assert!(replay.feed(&received(start + Duration::from_secs(3), master, Message::Reset)).is_empty());
+    let after = start + Duration::from_secs(4);
+    assert_eq!(replay.feed(&received(after, master, details(3))), vec![(after, Decision::Elected { addr: master, hid, term: 3 })]);
+}
+
+#[test]
+fn keeps_the_configured_cluster_size_in_later_terms() {
+    let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let nodes: Vec<(SocketAddr, Uuid, i32)> = (1..=2)
+        .map(|i| (format!("10.0.0.{}:43000", i).parse().unwrap(), Uuid::new_v4(), i))
+        .collect();
+    let (best_addr, best_hid, _) = nodes[1];
+    let mut records = vec![received(start, nodes[0].0, Message::ConnectionDetails(ConnectionDetails {
+        hid: nodes[0].1,
+        servers: vec![nodes[0].1],
+        artifacts: None,
+        term: 0,
+        api_addr: None,
+    }))];
+    // Both nodes move on to term 1 and vote for the same candidate.
+    for second in 1..40 {
+        for (addr, hid, priority) in &nodes {
+            let time = start + Duration::from_secs(second);
+            records.push(received(time, *addr, Message::CastVote(CastVote { addr: best_addr, hid: best_hid, term: 1 })));
+            records.push(received(time, *addr, appearance(*hid, *priority)));
+        }
+    }
+    let elected_in_term_one = |config: &BootstrapConfig| {
+        let mut replay = Replay::new(start, config);
+        records.iter().flat_map(|record| replay.feed(record)).any(|(_, decision)| {
+            decision == Decision::Elected { addr: best_addr, hid: best_hid, term: 1 }
+        })
+    };
+    assert!(elected_in_term_one(&BootstrapConfig::default()));
+    // Two of five are no majority, in the first term or any later one.
+    let sized = BootstrapConfig { cluster_size: Some(5), ..BootstrapConfig::default() };
+    assert!(!elected_in_term_one(&sized));
+}
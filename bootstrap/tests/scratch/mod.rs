@@ -0,0 +1,22 @@
+//! Paths under the system temp dir for tests that touch the filesystem,
+//! unique to the test process so that concurrent runs do not collide.
+
+#![allow(dead_code)]
+
+use std::path::PathBuf;
+
+/// Path for the file or directory `name` of the test suite `suite`, with
+/// whatever an earlier run left there removed.
+pub fn scratch(suite: &str, name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("homesec-{}-{}-{}", suite, name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+/// Like `scratch`, but creates the directory.
+pub fn scratch_dir(suite: &str, name: &str) -> PathBuf {
+    let dir = scratch(suite, name);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
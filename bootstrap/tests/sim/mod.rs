@@ -0,0 +1,310 @@
+//! In-process simulation of several bootstrap daemons electing a master
+//! over a lossy broadcast bus. Every node shares one `ManualClock`, so a
+//! full election runs in milliseconds and is reproducible from a seed.
+//! Concluded nodes keep running: the master renews its lease, and any
+//! node that hears from a later term, or whose master's lease lapses,
+//! rejoins with a fresh election as the daemon does.
+
+#![allow(dead_code)]
+
+use homesec_bootstrap::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+/// Fault model applied to every datagram on the bus. Datagrams a node
+/// sends to itself are looped back immediately and never dropped.
+#[derive(Clone, Default)]
+pub struct BusConfig {
+    /// Probability that a datagram is dropped for a given receiver.
+    pub loss: f64,
+    /// Maximum number of extra ticks a datagram may be held back, which
+    /// reorders it relative to datagrams sent later.
+    pub max_delay: u64,
+}
+
+/// Drops every datagram between nodes in different groups while
+/// `from <= tick < until`.
+#[derive(Clone)]
+pub struct Partition {
+    pub groups: Vec<Vec<usize>>,
+    pub from: u64,
+    pub until: u64,
+}
+
+impl Partition {
+    fn separates(&self, tick: u64, a: usize, b: usize) -> bool {
+        if tick < self.from || tick >= self.until {
+            return false;
+        }
+        let group_of = |i| self.groups.iter().position(|g| g.contains(&i));
+        group_of(a) != group_of(b)
+    }
+}
+
+struct Packet {
+    deliver_at: u64,
+    from: usize,
+    to: usize,
+    data: Vec<u8>,
+}
+
+pub struct SimNode {
+    pub addr: SocketAddr,
+    pub hid: Uuid,
+    pub election: Election,
+    /// Settings the node advertises in its appearances.
+    pub config: BootstrapConfig,
+    /// The master this node settled on, once it has concluded.
+    pub outcome: Option<(SocketAddr, Uuid)>,
+    /// Term of `outcome`.
+    pub outcome_term: u64,
+    /// Set while a master that stood down runs for re-election, so that
+    /// its appearances claim to be master.
+    pub was_master: bool,
+    /// Tick at which the node loses power and stops sending or receiving.
+    pub down_at: Option<u64>,
+}
+
+impl SimNode {
+    fn is_up(&self, tick: u64) -> bool {
+        self.down_at.is_none_or(|down_at| tick < down_at)
+    }
+}
+
+pub struct Simulation {
+    pub clock: ManualClock,
+    pub nodes: Vec<SimNode>,
+    pub bus: BusConfig,
+    pub partitions: Vec<Partition>,
+    pub tick: u64,
+    /// Wall time that passes between two iterations of every node's loop.
+    pub interval: Duration,
+    /// Every `(term, master)` a node concluded on, in order.
+    pub results: Vec<(u64, Uuid)>,
+    rng: StdRng,
+    in_flight: Vec<Packet>,
+}
+
+impl Simulation {
+    pub fn new(count: usize, bus: BusConfig, seed: u64) -> Self {
+        let clock = ManualClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+        let mut rng = StdRng::seed_from_u64(seed);
+        let nodes = (0..count)
+            .map(|i| {
+                let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, i as u8 + 1)), 43000);
+                let hid = Uuid::from_u128(rng.gen());
+                let election = Election::with_clock(
+                    Arc::new(clock.clone()),
+                    Box::new(StdRng::seed_from_u64(rng.gen())),
+                );
+                SimNode {
+                    addr,
+                    hid,
+                    election,
+                    config: BootstrapConfig::default(),
+                    outcome: None,
+                    outcome_term: 0,
+                    was_master: false,
+                    down_at: None,
+                }
+            })
+            .collect();
+        Self {
+            clock,
+            nodes,
+            bus,
+            partitions: Vec::new(),
+            tick: 0,
+            interval: Duration::from_millis(1000),
+            results: Vec::new(),
+            rng,
+            in_flight: Vec::new(),
+        }
+    }
+
+    pub fn partition(mut self, partition: Partition) -> Self {
+        self.partitions.push(partition);
+        self
+    }
+
+    /// Cuts power to node `i` at `tick`.
+    pub fn power_off(mut self, i: usize, tick: u64) -> Self {
+        self.nodes[i].down_at = Some(tick);
+        self
+    }
+
+    /// Sets the configured cluster size of every node to `size`.
+    pub fn cluster_size(mut self, size: usize) -> Self {
+        for node in self.nodes.iter_mut() {
+            node.election.cluster_size = Some(size);
+        }
+        self
+    }
+
+    /// Starts a fresh election in the next term on every live node, as
+    /// agents do once they stop hearing from their master.
+    pub fn restart_election(&mut self) {
+        let tick = self.tick;
+        for i in 0..self.nodes.len() {
+            if self.nodes[i].is_up(tick) {
+                let term = self.nodes[i].election.term + 1;
+                self.rejoin(i, term);
+            }
+        }
+    }
+
+    /// Replaces node `i`'s election with a fresh one in `term`, keeping
+    /// its timings and configured cluster size.
+    fn rejoin(&mut self, i: usize, term: u64) {
+        let mut election = Election::with_clock(
+            Arc::new(self.clock.clone()),
+            Box::new(StdRng::seed_from_u64(self.rng.gen())),
+        );
+        let node = &mut self.nodes[i];
+        election.term = term;
+        election.delay = node.election.delay;
+        election.node_timeout = node.election.node_timeout;
+        election.cluster_size = node.election.cluster_size;
+        node.election = election;
+        node.outcome = None;
+    }
+
+    fn live_nodes(&self) -> impl Iterator<Item = &SimNode> {
+        let tick = self.tick;
+        self.nodes.iter().filter(move |n| n.is_up(tick))
+    }
+
+    fn broadcast(&mut self, from: usize, msg: &Message) {
+        let data = bincode::serialize(msg).unwrap();
+        for to in 0..self.nodes.len() {
+            let mut deliver_at = self.tick;
+            if to != from {
+                if self.rng.gen_bool(self.bus.loss)
+                    || self.partitions.iter().any(|p| p.separates(self.tick, from, to))
+                {
+                    continue;
+                }
+                if self.bus.max_delay > 0 {
+                    deliver_at += self.rng.gen_range(0, self.bus.max_delay + 1);
+                }
+            }
+            self.in_flight.push(Packet { deliver_at, from, to, data: data.clone() });
+        }
+    }
+
+    fn deliver(&mut self, to: usize) {
+        let tick = self.tick;
+        let (ready, pending): (Vec<_>, Vec<_>) = self
+            .in_flight
+            .drain(..)
+            .partition(|p| p.to == to && p.deliver_at <= tick);
+        self.in_flight = pending;
+        for packet in ready {
+            let source = self.nodes[packet.from].addr;
+            let msg: Message = bincode::deserialize(&packet.data).unwrap();
+            // The daemon aborts on these; the simulation only cares about
+            // the state machine, so a stray vote is simply ignored.
+            let _ = self.nodes[to].election.process_message(source, &msg);
+        }
+    }
+
+    /// Runs one iteration of `elect_master` on every node, of the
+    /// master's announcement loop for a node that has won, or of
+    /// `watch_master` for one that follows another.
+    pub fn step(&mut self) {
+        for i in 0..self.nodes.len() {
+            if !self.nodes[i].is_up(self.tick) {
+                continue;
+            }
+            self.deliver(i);
+            let node = &mut self.nodes[i];
+            if let Some((_, master)) = node.outcome {
+                node.election.expire_nodes();
+                if node.election.term > node.outcome_term {
+                    node.was_master = master == node.hid;
+                    let term = node.election.term;
+                    self.rejoin(i, term);
+                } else if master == node.hid {
+                    let msg = Message::ConnectionDetails(ConnectionDetails {
+                        hid: master,
+                        servers: vec![master],
+                        artifacts: None,
+                        term: node.outcome_term,
+                        api_addr: None,
+                    });
+                    self.broadcast(i, &msg);
+                    continue;
+                } else if node.election.nodes.iter().all(|n| n.hid != master) {
+                    // The master's lease lapsed.
+                    let term = node.outcome_term + 1;
+                    self.rejoin(i, term);
+                } else {
+                    continue;
+                }
+            }
+            let node = &mut self.nodes[i];
+            let mut outgoing = Vec::new();
+            if let Some((addr, hid)) = node.election.check_result() {
+                let term = node.election.term;
+                node.outcome = Some((addr, hid));
+                node.outcome_term = term;
+                node.was_master = false;
+                self.results.push((term, hid));
+                outgoing.push(Message::ElectionResult(ElectionResult { addr, hid, term }));
+            } else {
+                // The daemon repeats its vote with every appearance.
+                node.election.check_vote();
+                if let Some((addr, hid)) = node.election.voted_for {
+                    outgoing.push(Message::CastVote(CastVote { addr, hid, term: node.election.term }));
+                }
+                outgoing.push(Message::Appearance(AppearanceMessage {
+                    priority: node.election.priority,
+                    hid: node.hid,
+                    is_master: node.was_master,
+                    static_priority: node.config.priority,
+                    master_eligible: node.config.master_eligible,
+                    labels: node.config.labels.clone(),
+                    term: node.election.term,
+                    role: None,
+                }));
+            }
+            for msg in &outgoing {
+                self.broadcast(i, msg);
+            }
+        }
+        self.tick += 1;
+        self.clock.advance(self.interval);
+    }
+
+    /// Steps until every node has concluded, returning false if that does
+    /// not happen within `max_ticks`.
+    pub fn run(&mut self, max_ticks: u64) -> bool {
+        while self.tick < max_ticks {
+            if self.live_nodes().all(|n| n.outcome.is_some()) {
+                return true;
+            }
+            self.step();
+        }
+        false
+    }
+
+    /// Asserts that every live node concluded on the same live master.
+    pub fn assert_agreement(&self) -> Uuid {
+        let outcomes = self
+            .live_nodes()
+            .map(|n| n.outcome.expect("node never concluded").1)
+            .collect::<Vec<_>>();
+        let master = outcomes[0];
+        assert!(
+            outcomes.iter().all(|hid| *hid == master),
+            "nodes disagree on outcome of election: {:?}",
+            outcomes
+        );
+        assert!(self.live_nodes().any(|n| n.hid == master), "elected a node that is down");
+        master
+    }
+}
@@ -0,0 +1,300 @@
+mod sim;
+
+use homesec_bootstrap::*;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use sim::*;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+#[test]
+fn elects_single_master_on_perfect_bus() {
+    let mut sim = Simulation::new(5, BusConfig::default(), 1);
+    assert!(sim.run(120), "election did not conclude");
+    let master = sim.assert_agreement();
+    let expected = sim
+        .nodes
+        .iter()
+        .max_by_key(|n| (n.election.priority, n.hid))
+        .unwrap()
+        .hid;
+    assert_eq!(master, expected, "highest priority node was not elected");
+}
+
+#[test]
+fn agrees_despite_loss_and_reordering() {
+    let bus = BusConfig {
+        loss: 0.1,
+        max_delay: 2,
+    };
+    for seed in 0..20 {
+        let mut sim = Simulation::new(7, bus.clone(), seed);
+        assert!(sim.run(300), "seed {}: election did not conclude", seed);
+        sim.assert_agreement();
+    }
+}
+
+#[test]
+fn agrees_after_partition_heals() {
+    for seed in 0..10 {
+        let mut sim = Simulation::new(6, BusConfig::default(), seed).partition(Partition {
+            groups: vec![vec![0, 1, 2], vec![3, 4, 5]],
+            from: 0,
+            until: 8,
+        });
+        assert!(sim.run(300), "seed {}: election did not conclude", seed);
+        sim.assert_agreement();
+    }
+}
+
+#[test]
+fn prefers_static_priority_over_random_priority() {
+    for seed in 0..10 {
+        let mut sim = Simulation::new(5, BusConfig::default(), seed);
+        let weakest = (0..5).min_by_key(|&i| sim.nodes[i].election.priority).unwrap();
+        sim.nodes[weakest].config.priority = 10;
+        assert!(sim.run(120), "seed {}: election did not conclude", seed);
+        assert_eq!(sim.assert_agreement(), sim.nodes[weakest].hid);
+    }
+}
+
+#[test]
+fn never_elects_ineligible_nodes() {
+    for seed in 0..10 {
+        let mut sim = Simulation::new(5, BusConfig::default(), seed);
+        for node in sim.nodes.iter_mut().take(4) {
+            node.config.master_eligible = false;
+            node.config.priority = 100;
+        }
+        assert!(sim.run(120), "seed {}: election did not conclude", seed);
+        assert_eq!(sim.assert_agreement(), sim.nodes[4].hid);
+        for node in &sim.nodes {
+            assert_eq!(node.election.ranking().len(), 1);
+        }
+    }
+}
+
+#[test]
+fn evicts_nodes_that_lose_power() {
+    // Two of five nodes vanish after their first appearances. Without
+    // eviction the remaining three can never reach a quorum of four.
+    for seed in 0..10 {
+        let mut sim = Simulation::new(5, BusConfig::default(), seed)
+            .power_off(3, 2)
+            .power_off(4, 2);
+        for node in sim.nodes.iter_mut() {
+            node.election.node_timeout = Duration::from_secs(5);
+        }
+        assert!(sim.run(120), "seed {}: election did not conclude", seed);
+        sim.assert_agreement();
+    }
+}
+
+#[test]
+fn needs_a_majority_of_the_configured_cluster() {
+    // Three of five nodes are a majority and elect a master without the
+    // other two; two could be one side of a partition and must not.
+    for seed in 0..10 {
+        let mut sim = Simulation::new(5, BusConfig::default(), seed)
+            .cluster_size(5)
+            .power_off(3, 2)
+            .power_off(4, 2);
+        for node in sim.nodes.iter_mut() {
+            node.election.node_timeout = Duration::from_secs(5);
+        }
+        assert!(sim.run(120), "seed {}: election did not conclude", seed);
+        sim.assert_agreement();
+        let mut sim = Simulation::new(5, BusConfig::default(), seed)
+            .cluster_size(5)
+            .power_off(2, 2)
+            .power_off(3, 2)
+            .power_off(4, 2);
+        for node in sim.nodes.iter_mut() {
+            node.election.node_timeout = Duration::from_secs(5);
+        }
+        assert!(!sim.run(120), "seed {}: a minority elected a master", seed);
+    }
+}
+
+#[test]
+fn discards_votes_from_evicted_nodes() {
+    let clock = ManualClock::new(SystemTime::UNIX_EPOCH);
+    let mut election = Election::with_clock(Arc::new(clock.clone()), Box::new(StdRng::seed_from_u64(0)));
+    election.node_timeout = Duration::from_secs(5);
+    let a: SocketAddr = "10.0.0.1:43000".parse().unwrap();
+    let b: SocketAddr = "10.0.0.2:43000".parse().unwrap();
+    let appearance = |hid| {
+        Message::Appearance(AppearanceMessage {
+            priority: 1,
+            is_master: false,
+            hid,
+            static_priority: 0,
+            master_eligible: true,
+            labels: Default::default(),
+            term: 0,
+            role: None,
+        })
+    };
+    let (hid_a, hid_b) = (Uuid::from_u128(1), Uuid::from_u128(2));
+    election.process_message(a, &appearance(hid_a)).unwrap();
+    election.process_message(b, &appearance(hid_b)).unwrap();
+    election.cast_vote(a, hid_a, b).unwrap();
+    clock.advance(Duration::from_secs(3));
+    election.process_message(a, &appearance(hid_a)).unwrap();
+    clock.advance(Duration::from_secs(3));
+    election.check_result();
+    assert_eq!(election.nodes.len(), 1);
+    assert_eq!(election.nodes[0].hid, hid_a);
+    assert!(election.nodes[0].votes.is_empty());
+}
+
+#[test]
+fn forgets_nodes_that_leave() {
+    let clock = ManualClock::new(SystemTime::UNIX_EPOCH);
+    let mut election = Election::with_clock(Arc::new(clock), Box::new(StdRng::seed_from_u64(0)));
+    let a: SocketAddr = "10.0.0.1:43000".parse().unwrap();
+    let b: SocketAddr = "10.0.0.2:43000".parse().unwrap();
+    let appearance = |hid| {
+        Message::Appearance(AppearanceMessage {
+            priority: 1,
+            is_master: false,
+            hid,
+            static_priority: 0,
+            master_eligible: true,
+            labels: Default::default(),
+            term: 0,
+            role: None,
+        })
+    };
+    let (hid_a, hid_b) = (Uuid::from_u128(1), Uuid::from_u128(2));
+    election.process_message(a, &appearance(hid_a)).unwrap();
+    election.process_message(b, &appearance(hid_b)).unwrap();
+    election.cast_vote(a, hid_a, b).unwrap();
+    election.process_message(b, &Message::Leave(LeaveMessage { hid: hid_b })).unwrap();
+    assert_eq!(election.nodes.len(), 1);
+    assert_eq!(election.nodes[0].hid, hid_a);
+    assert!(election.nodes[0].votes.is_empty(), "vote from the departed node still counts");
+}
+
+#[test]
+fn replaces_lost_master() {
+    for seed in 0..10 {
+        let mut sim = Simulation::new(5, BusConfig::default(), seed);
+        assert!(sim.run(120), "seed {}: election did not conclude", seed);
+        let old = sim.assert_agreement();
+        let i = sim.nodes.iter().position(|n| n.hid == old).unwrap();
+        sim.nodes[i].down_at = Some(sim.tick);
+        sim.restart_election();
+        assert!(sim.run(sim.tick + 120), "seed {}: re-election did not conclude", seed);
+        assert_ne!(sim.assert_agreement(), old);
+    }
+}
+
+#[test]
+fn ranking_starts_with_elected_master() {
+    let mut sim = Simulation::new(6, BusConfig::default(), 3);
+    assert!(sim.run(120), "election did not conclude");
+    let master = sim.assert_agreement();
+    for node in &sim.nodes {
+        let ranking = node.election.ranking();
+        assert_eq!(ranking.len(), 6);
+        assert_eq!(ranking[0].1, master);
+    }
+}
+
+#[test]
+fn follows_only_the_latest_lease() {
+    let clock = ManualClock::new(SystemTime::UNIX_EPOCH);
+    let mut election = Election::with_clock(Arc::new(clock), Box::new(StdRng::seed_from_u64(0)));
+    election.term = 2;
+    let a: SocketAddr = "10.0.0.1:43000".parse().unwrap();
+    let b: SocketAddr = "10.0.0.2:43000".parse().unwrap();
+    let details = |hid, term| {
+        Message::ConnectionDetails(ConnectionDetails {
+            hid,
+            servers: vec![hid],
+            artifacts: None,
+            term,
+            api_addr: None,
+        })
+    };
+    let (hid_a, hid_b) = (Uuid::from_u128(1), Uuid::from_u128(2));
+    election.process_message(a, &details(hid_a, 1)).unwrap();
+    assert_eq!(election.check_result(), None, "followed a master from an earlier term");
+    election.process_message(b, &details(hid_b, 3)).unwrap();
+    assert_eq!(election.check_result(), Some((b, hid_b)));
+    assert_eq!(election.term, 3);
+}
+
+#[test]
+fn votes_again_in_a_later_term() {
+    let clock = ManualClock::new(SystemTime::UNIX_EPOCH);
+    let mut election = Election::with_clock(Arc::new(clock.clone()), Box::new(StdRng::seed_from_u64(0)));
+    let a: SocketAddr = "10.0.0.1:43000".parse().unwrap();
+    let b: SocketAddr = "10.0.0.2:43000".parse().unwrap();
+    let appearance = |hid, priority, term| {
+        Message::Appearance(AppearanceMessage {
+            priority,
+            is_master: false,
+            hid,
+            static_priority: 0,
+            master_eligible: true,
+            labels: Default::default(),
+            term,
+            role: None,
+        })
+    };
+    let (hid_a, hid_b) = (Uuid::from_u128(1), Uuid::from_u128(2));
+    election.process_message(a, &appearance(hid_a, 1, 0)).unwrap();
+    election.process_message(b, &appearance(hid_b, 2, 0)).unwrap();
+    clock.advance(election.delay);
+    assert_eq!(election.check_vote(), Some((b, hid_b)));
+    assert_eq!(election.check_vote(), None, "voted twice in one term");
+    election.process_message(a, &Message::CastVote(CastVote { addr: b, hid: hid_b, term: 1 })).unwrap();
+    assert_eq!(election.term, 1);
+    assert_eq!(election.nodes.iter().map(|n| n.votes.len()).sum::<usize>(), 1);
+    clock.advance(election.delay);
+    assert_eq!(election.check_vote(), Some((b, hid_b)));
+}
+
+#[test]
+fn elects_among_existing_servers_only() {
+    let clock = ManualClock::new(SystemTime::UNIX_EPOCH);
+    let mut election = Election::with_clock(Arc::new(clock), Box::new(StdRng::seed_from_u64(0)));
+    let appearance = |hid, static_priority, role| {
+        Message::Appearance(AppearanceMessage {
+            priority: 1,
+            is_master: false,
+            hid,
+            static_priority,
+            master_eligible: true,
+            labels: Default::default(),
+            term: 0,
+            role,
+        })
+    };
+    let hids = |election: &Election| election.ranking().into_iter().map(|(_, hid)| hid).collect::<Vec<_>>();
+    let (agent, fresh, server, master) = (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3), Uuid::from_u128(4));
+    election.process_message("10.0.0.1:43000".parse().unwrap(), &appearance(agent, 10, Some(Role::Agent))).unwrap();
+    election.process_message("10.0.0.2:43000".parse().unwrap(), &appearance(fresh, 5, None)).unwrap();
+    assert_eq!(hids(&election), [agent, fresh]);
+    election.process_message("10.0.0.3:43000".parse().unwrap(), &appearance(server, 0, Some(Role::Server))).unwrap();
+    election.process_message("10.0.0.4:43000".parse().unwrap(), &appearance(master, 1, Some(Role::Master))).unwrap();
+    assert_eq!(hids(&election), [master, server]);
+    assert_eq!(election.servers(), [server, master]);
+}
+
+#[test]
+fn draws_a_new_priority_in_each_term() {
+    let clock = ManualClock::new(SystemTime::UNIX_EPOCH);
+    let mut election = Election::with_clock(Arc::new(clock), Box::new(StdRng::seed_from_u64(0)));
+    let mut priorities = vec![election.priority];
+    for term in 1..5 {
+        election.enter_term(term);
+        priorities.push(election.priority);
+    }
+    priorities.dedup();
+    assert!(priorities.len() > 1, "kept priority {:?} in every term", priorities);
+}
@@ -0,0 +1,68 @@
+mod scratch;
+
+use homesec_bootstrap::*;
+use scratch::*;
+use uuid::Uuid;
+
+#[test]
+fn starts_discovering_without_a_state_file() {
+    let state = StateFile::open(scratch("state", "missing").join("bootstrap.json")).unwrap();
+    assert!(!state.exists());
+    assert_eq!(state.state(), &BootstrapState::default());
+    assert_eq!(state.state().phase, Phase::Discovering);
+}
+
+#[test]
+fn persists_every_update() {
+    let path = scratch("state", "persist").join("nested").join("bootstrap.json");
+    let master = Uuid::new_v4();
+    let addr = "192.168.1.10:43000".parse().unwrap();
+    let mut state = StateFile::open(&path).unwrap();
+    state.begin_install(Role::Agent).unwrap();
+    let reloaded = StateFile::open(&path).unwrap();
+    assert_eq!(reloaded.state().phase, Phase::Installing);
+    assert_eq!(reloaded.state().installing, Some(Role::Agent));
+    assert_eq!(reloaded.state().role, None);
+
+    state.joined(Role::Agent, master, Some(addr), Some("v1.27.4+k3s1".into())).unwrap();
+    let reloaded = StateFile::open(&path).unwrap();
+    assert_eq!(reloaded.state(), state.state());
+    assert!(reloaded.state().has_joined(Role::Agent, master));
+    assert!(!reloaded.state().has_joined(Role::Agent, Uuid::new_v4()));
+    assert!(!reloaded.state().has_joined(Role::Server, master));
+    assert_eq!(reloaded.state().master_addr, Some(addr));
+    assert!(!path.with_extension("json.tmp").exists());
+
+    let servers = [master, Uuid::new_v4()];
+    state.set_servers(&servers).unwrap();
+    assert_eq!(StateFile::open(&path).unwrap().state().servers, servers);
+}
+
+#[test]
+fn failure_keeps_the_completed_role() {
+    let path = scratch("state", "failure").join("bootstrap.json");
+    let master = Uuid::new_v4();
+    let mut state = StateFile::open(&path).unwrap();
+    state.joined(Role::Server, master, None, None).unwrap();
+    state.fail("timed out waiting for connection details from master").unwrap();
+    let reloaded = StateFile::open(&path).unwrap();
+    assert_eq!(reloaded.state().phase, Phase::Failed);
+    assert_eq!(reloaded.state().role, Some(Role::Server));
+    assert!(!reloaded.state().has_joined(Role::Server, master));
+    assert!(reloaded.state().error.as_deref().unwrap().contains("timed out"));
+}
+
+#[test]
+fn reads_kebab_case_phases_and_tolerates_missing_fields() {
+    let path = scratch("state", "format").join("bootstrap.json");
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(&path, r#"{"phase": "joined-as-master", "role": "master"}"#).unwrap();
+    let state = StateFile::open(&path).unwrap();
+    assert_eq!(state.state().phase, Phase::JoinedAsMaster);
+    assert_eq!(state.state().role, Some(Role::Master));
+    assert_eq!(state.state().master_hid, None);
+    assert!(state.state().servers.is_empty());
+
+    std::fs::write(&path, "{").unwrap();
+    assert!(StateFile::open(&path).is_err());
+}
@@ -0,0 +1,131 @@
+use homesec_bootstrap::*;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+const KEY: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+/// A raw sender, and a pump receiving on a loopback socket that is the
+/// sender's target.
+fn loopback() -> (UdpSocket, SocketAddr, Pump) {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_nonblocking(true).unwrap();
+    let addr = socket.local_addr().unwrap();
+    let auth = Authenticator::new(KEY.to_vec()).unwrap();
+    let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let pump = Pump::new(&Transport::new(socket, vec![addr], auth)).unwrap();
+    (sender, addr, pump)
+}
+
+/// Reads until a message arrives or the socket stays quiet.
+async fn recv(pump: &mut Pump) -> Option<(SocketAddr, Message)> {
+    tokio::time::timeout(Duration::from_millis(500), pump.recv()).await.ok().map(Result::unwrap)
+}
+
+#[tokio::test]
+async fn survives_garbage_datagrams() {
+    let (sender, target, mut pump) = loopback();
+    sender.send_to(b"garbage", target).unwrap();
+    sender.send_to(&[0xff; 9000], target).unwrap();
+    let codec = Codec::new(Authenticator::new(KEY.to_vec()).unwrap());
+    sender.send_to(&codec.encode(&Message::Reset).unwrap(), target).unwrap();
+    let (source, msg) = recv(&mut pump).await.expect("valid message was not received");
+    assert_eq!(msg, Message::Reset);
+    assert_eq!(pump.filter().stats(&source).unwrap().dropped, 2);
+}
+
+#[tokio::test]
+async fn ignores_sources_that_keep_sending_garbage() {
+    let (sender, target, mut pump) = loopback();
+    for _ in 0..BAD_PACKET_LIMIT {
+        sender.send_to(b"garbage", target).unwrap();
+    }
+    let codec = Codec::new(Authenticator::new(KEY.to_vec()).unwrap());
+    sender.send_to(&codec.encode(&Message::Reset).unwrap(), target).unwrap();
+    assert!(recv(&mut pump).await.is_none(), "blocked source was decoded");
+}
+
+#[test]
+fn unblocks_sources_after_cooldown() {
+    let clock = ManualClock::new(SystemTime::UNIX_EPOCH);
+    let mut filter = SourceFilter::new(Arc::new(clock.clone()));
+    let addr: SocketAddr = "10.0.0.1:43000".parse().unwrap();
+    for _ in 0..BAD_PACKET_LIMIT - 1 {
+        filter.record(addr);
+    }
+    assert!(!filter.is_blocked(&addr));
+    // A slow trickle of bad packets never trips the limit.
+    clock.advance(BAD_PACKET_WINDOW + Duration::from_secs(1));
+    filter.record(addr);
+    assert!(!filter.is_blocked(&addr));
+    for _ in 0..BAD_PACKET_LIMIT {
+        filter.record(addr);
+    }
+    assert!(filter.is_blocked(&addr));
+    clock.advance(BLOCK_DURATION);
+    assert!(!filter.is_blocked(&addr));
+    assert_eq!(filter.stats(&addr).unwrap().dropped, 2 * BAD_PACKET_LIMIT as u64 + 1);
+}
+
+#[test]
+fn blocks_every_port_of_a_blocked_source() {
+    let clock = ManualClock::new(SystemTime::UNIX_EPOCH);
+    let mut filter = SourceFilter::new(Arc::new(clock.clone()));
+    let addr: SocketAddr = "10.0.0.1:43000".parse().unwrap();
+    for port in 0..BAD_PACKET_LIMIT as u16 {
+        filter.record(SocketAddr::new(addr.ip(), 50000 + port));
+    }
+    assert!(filter.is_blocked(&addr));
+    assert!(filter.is_blocked(&"10.0.0.1:43001".parse().unwrap()));
+    assert!(!filter.is_blocked(&"10.0.0.2:43000".parse().unwrap()));
+}
+
+#[test]
+fn forgets_idle_sources_and_caps_the_rest() {
+    let clock = ManualClock::new(SystemTime::UNIX_EPOCH);
+    let mut filter = SourceFilter::new(Arc::new(clock.clone()));
+    let source = |i: usize| SocketAddr::from(([10, (i >> 16) as u8, (i >> 8) as u8, i as u8], 43000));
+    for i in 0..MAX_TRACKED_SOURCES {
+        filter.record(source(i));
+    }
+    let extra = source(MAX_TRACKED_SOURCES);
+    filter.record(extra);
+    assert!(filter.stats(&extra).is_none(), "tracked more than {} sources", MAX_TRACKED_SOURCES);
+    clock.advance(BAD_PACKET_WINDOW + Duration::from_secs(1));
+    filter.record(extra);
+    assert_eq!(filter.stats(&extra).unwrap().dropped, 1);
+    assert!(filter.stats(&source(0)).is_none(), "idle source was kept");
+}
+
+#[tokio::test]
+async fn drops_duplicates_without_blocking_the_sender() {
+    let (sender, target, mut pump) = loopback();
+    let codec = Codec::new(Authenticator::new(KEY.to_vec()).unwrap());
+    let encoded = codec.encode(&Message::Reset).unwrap();
+    for _ in 0..BAD_PACKET_LIMIT * 2 {
+        sender.send_to(&encoded, target).unwrap();
+    }
+    let (source, msg) = recv(&mut pump).await.expect("first copy was not received");
+    assert_eq!(msg, Message::Reset);
+    assert!(recv(&mut pump).await.is_none());
+    assert_eq!(pump.auth().stats.replayed, BAD_PACKET_LIMIT as u64 * 2 - 1);
+    assert!(pump.filter().stats(&source).is_none());
+    sender.send_to(&codec.encode(&Message::Reset).unwrap(), target).unwrap();
+    assert!(recv(&mut pump).await.is_some());
+}
+
+#[tokio::test]
+async fn delivers_messages_in_order_to_the_inbox() {
+    let (sender, target, pump) = loopback();
+    let mut inbox = pump.spawn();
+    let codec = Codec::new(Authenticator::new(KEY.to_vec()).unwrap());
+    let leave = Message::Leave(LeaveMessage { hid: uuid::Uuid::new_v4() });
+    sender.send_to(&codec.encode(&Message::Reset).unwrap(), target).unwrap();
+    sender.send_to(b"garbage", target).unwrap();
+    sender.send_to(&codec.encode(&leave).unwrap(), target).unwrap();
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(1);
+    assert_eq!(inbox.recv_until(deadline).await.unwrap().unwrap().1, Message::Reset);
+    assert_eq!(inbox.recv_until(deadline).await.unwrap().unwrap().1, leave);
+    let quiet = tokio::time::Instant::now() + Duration::from_millis(50);
+    assert!(inbox.recv_until(quiet).await.unwrap().is_none());
+}
@@ -0,0 +1,115 @@
+use homesec_bootstrap::*;
+use serde::Serialize;
+use uuid::Uuid;
+
+const KEY: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+fn codec() -> Codec {
+    Codec::new(Authenticator::new(KEY.to_vec()).unwrap())
+}
+
+fn samples() -> Vec<Message> {
+    let hid = Uuid::from_u128(0x1234);
+    let addr = "10.0.0.1:43000".parse().unwrap();
+    vec![
+        Message::Appearance(AppearanceMessage {
+            priority: 7,
+            is_master: false,
+            hid,
+            static_priority: 5,
+            master_eligible: true,
+            labels: vec![("homesec/storage".to_string(), "ssd".to_string())].into_iter().collect(),
+            term: 2,
+            role: Some(Role::Server),
+        }),
+        Message::CastVote(CastVote { addr, hid, term: 2 }),
+        Message::Reset,
+        Message::ElectionResult(ElectionResult { addr, hid, term: 2 }),
+        Message::ConnectionDetails(ConnectionDetails {
+            hid,
+            servers: vec![hid, Uuid::from_u128(0x5678)],
+            artifacts: Some(ArtifactSource {
+                port: 43080,
+                sums: [7; 32],
+            }),
+            term: 2,
+            api_addr: Some("fd00::1".parse().unwrap()),
+        }),
+        Message::JoinRequest(JoinRequest { hid }),
+        Message::JoinResponse(JoinResponse {
+            hid,
+            master: hid,
+            nonce: [1; 12],
+            token: vec![1, 2, 3],
+        }),
+        Message::Leave(LeaveMessage { hid }),
+    ]
+}
+
+/// Builds a datagram by hand, as a node speaking another protocol
+/// version would.
+fn frame<T: Serialize>(version: u16, kind: u16, body: &T) -> Vec<u8> {
+    let mut data = MAGIC.to_vec();
+    data.extend_from_slice(&version.to_be_bytes());
+    data.extend_from_slice(&kind.to_be_bytes());
+    let auth = Authenticator::new(KEY.to_vec()).unwrap();
+    let envelope = auth.seal(&data, bincode::serialize(body).unwrap()).unwrap();
+    data.extend_from_slice(&envelope);
+    data
+}
+
+#[test]
+fn round_trips_every_kind() {
+    let sender = codec();
+    let mut receiver = codec();
+    for msg in samples() {
+        let data = sender.encode(&msg).unwrap();
+        assert_eq!(&data[..4], &MAGIC);
+        match receiver.decode(&data).unwrap() {
+            Decoded::Message(decoded) => assert_eq!(decoded, msg),
+            Decoded::Skipped(header) => panic!("skipped known kind {}", header.kind),
+        }
+    }
+}
+
+#[test]
+fn skips_unknown_kinds() {
+    let data = frame(PROTOCOL_VERSION + 1, 999, &(1u32, 2u32));
+    match codec().decode(&data).unwrap() {
+        Decoded::Skipped(header) => assert_eq!(header.kind, 999),
+        Decoded::Message(msg) => panic!("decoded unknown kind as {:?}", msg),
+    }
+}
+
+#[test]
+fn ignores_fields_appended_by_newer_versions() {
+    #[derive(Serialize)]
+    struct FutureJoinRequest {
+        hid: Uuid,
+        labels: Vec<String>,
+    }
+    let hid = Uuid::from_u128(42);
+    let data = frame(
+        PROTOCOL_VERSION + 1,
+        kind::JOIN_REQUEST,
+        &FutureJoinRequest {
+            hid,
+            labels: vec![String::from("ssd")],
+        },
+    );
+    match codec().decode(&data).unwrap() {
+        Decoded::Message(msg) => assert_eq!(msg, Message::JoinRequest(JoinRequest { hid })),
+        Decoded::Skipped(_) => panic!("skipped a known kind"),
+    }
+}
+
+#[test]
+fn rejects_garbage() {
+    let mut receiver = codec();
+    assert!(matches!(receiver.decode(b""), Err(DecodeError::Malformed)));
+    assert!(matches!(receiver.decode(b"not a bootstrap datagram"), Err(DecodeError::Malformed)));
+    let legacy = bincode::serialize(&Message::Reset).unwrap();
+    assert!(matches!(receiver.decode(&legacy), Err(DecodeError::Malformed)));
+    let truncated = frame(PROTOCOL_VERSION, kind::CAST_VOTE, &1u8);
+    assert!(matches!(receiver.decode(&truncated), Err(DecodeError::Malformed)));
+}
@@ -14,8 +14,7 @@ use std::net::{SocketAddr, UdpSocket};
 use std::process::Command;
 use std::time::{Duration, SystemTime};
 
-use homesec_bootstrap::{AppearanceMessage, Message};
-use std::collections::{HashMap, HashSet};
+use homesec_bootstrap::{Authenticator, Codec, Decoded, ElectionObserver, KEY_PATH};
 
 const BUFFER_SIZE: usize = 8192;
 
@@ -80,6 +79,13 @@ async fn install_bootstrap(address: &str) -> Result<()> {
         std::io::stderr().write_all(&output.stderr).unwrap();
         return Err(anyhow!("command failed with exit code {}", output.status));
     }
+    let dest = format!("pi@{}:/tmp/homesec-key", address);
+    let output = Command::new("scp").args(&[&get_key_path(), &dest]).output()?;
+    if !output.status.success() {
+        std::io::stdout().write_all(&output.stdout).unwrap();
+        std::io::stderr().write_all(&output.stderr).unwrap();
+        return Err(anyhow!("command failed with exit code {}", output.status));
+    }
     let dest = format!("pi@{}:/tmp/homesec-bootstrap", address);
     let output = Command::new("scp")
         .args(&[
@@ -93,7 +99,7 @@ async fn install_bootstrap(address: &str) -> Result<()> {
         std::io::stderr().write_all(&output.stderr).unwrap();
         return Err(anyhow!("command failed with exit code {}", output.status));
     }
-    let encoded = base64::encode("set -e; rm /usr/bin/homesec-bootstrap; mv /tmp/homesec-bootstrap /usr/bin/homesec-bootstrap; systemctl restart homesec-bootstrap.service");
+    let encoded = base64::encode("set -e; mv /tmp/homesec-key /etc/homesec-key; chmod 600 /etc/homesec-key; rm /usr/bin/homesec-bootstrap; mv /tmp/homesec-bootstrap /usr/bin/homesec-bootstrap; systemctl restart homesec-bootstrap.service");
     let output = Command::new("ssh")
         .args(&[
             &format!("pi@{}", &address),
@@ -119,7 +125,7 @@ fn uninstall_k3s(address: &str) -> Result<()> {
         .args(&[
             "-c",
             &format!(
-                "set -e; ssh pi@{} sudo /usr/bin/homesec-bootstrap remove",
+                "set -e; ssh pi@{} sudo /usr/bin/homesec-bootstrap remove --force",
                 address
             ),
         ])
@@ -147,7 +153,8 @@ fn uninstall_k3s(address: &str) -> Result<()> {
 fn ensure_uninstalled(address: &str) -> Result<()> {
     let encoded = base64::encode(
         "set -e;\
-if [[ -n \"$(ls /etc | grep k3s-master)\" ]]; then exit 100; fi;\
+if [[ -n \"$(ls /etc | grep -E 'k3s-(master|role)')\" ]]; then exit 100; fi;\
+if [[ -e /var/lib/homesec/bootstrap.json ]]; then exit 100; fi;\
 if [[ -n \"$(ls /etc | grep rancher)\" ]]; then exit 101; fi;\
 if [[ -n \"$(ls /var/lib | grep rancher)\" ]]; then exit 102; fi;\
 if [[ -n \"$(ls /etc/systemd/system | grep homesec-bootstrap.service)\" ]]; then exit 103; fi;",
@@ -264,6 +271,10 @@ fn get_port() -> Result<i32> {
     return Ok(43000);
 }
 
+fn get_key_path() -> String {
+    std::env::var("CLUSTER_KEY_PATH").unwrap_or_else(|_| String::from(KEY_PATH))
+}
+
 fn retrieve_kubeconfig(addr: SocketAddr) -> Result<()> {
     Ok(())
 }
@@ -271,6 +282,7 @@ fn retrieve_kubeconfig(addr: SocketAddr) -> Result<()> {
 #[tokio::main]
 async fn main() -> Result<()> {
     let port = get_port()?;
+    let mut codec = Codec::new(Authenticator::from_file(get_key_path())?);
     let mut socket = UdpSocket::bind(format!("0.0.0.0:{}", port))?;
     socket.set_nonblocking(true)?;
     socket.set_broadcast(true)?;
@@ -283,11 +295,7 @@ async fn main() -> Result<()> {
     let timeout = Duration::from_secs(240);
     let start = SystemTime::now();
     println!("waiting for master election");
-    let mut votes = HashMap::<SocketAddr, HashSet<SocketAddr>>::new();
-    let mut appearances = HashSet::<SocketAddr>::new();
-    let mut result_count = 0;
-    let mut master = None;
-    let mut election_results = Vec::new();
+    let mut observer = ElectionObserver::new();
     loop {
         let elapsed = SystemTime::now().duration_since(start).unwrap();
         if elapsed > timeout {
@@ -295,60 +303,17 @@ async fn main() -> Result<()> {
         }
         match socket.recv_from(&mut buf) {
             Ok((n, addr)) => {
-                let msg: Message = bincode::deserialize(&buf[..n])?;
-                match msg {
-                    Message::Reset => {
-                        return Err(anyhow!("observed unexpected election reset"));
-                    }
-                    Message::Appearance(msg) => {
-                        if appearances.insert(addr) {
-                            println!(
-                                "appearance from {}, is_master={}, hid={}, priority={}",
-                                addr, msg.is_master, msg.hid, msg.priority
-                            );
-                        }
-                    }
-                    Message::CastVote(vote) => {
-                        let cast = match votes.get_mut(&vote.addr) {
-                            Some(votes) => votes.insert(addr),
-                            None => {
-                                let mut v = HashSet::<SocketAddr>::new();
-                                v.insert(addr);
-                                votes.insert(vote.addr, v);
-                                true
-                            }
-                        };
-                        if cast {
-                            println!("{} cast vote for {}", addr, vote.addr);
-                        }
-                    }
-                    Message::ConnectionDetails(details) => {
-                        if let Some(master) = master {
-                            return Err(anyhow!(
-                                "observed multiple ConnectionDetails from {} and {}",
-                                master,
-                                addr
-                            ));
-                        } else {
-                            println!("observed k3s connection details for master, addr={}, hid={}, token={}", addr, details.hid, &details.token);
-                            master = Some(addr);
-                        }
-                        if result_count == addresses.len() {
-                            break;
-                        }
-                    }
-                    Message::ElectionResult(result) => {
-                        election_results.push(result.addr);
-                        println!(
-                            "observed election result, source={}, candidate={}, hid={}",
-                            addr, result.addr, result.hid
-                        );
-                        result_count += 1;
-                        if result_count == addresses.len() && master.is_some() {
-                            break;
-                        }
+                let msg = match codec.decode(&buf[..n]) {
+                    Ok(Decoded::Message(msg)) => msg,
+                    Ok(Decoded::Skipped(_)) => continue,
+                    Err(e) => {
+                        println!("ignoring {} packet from {}", e, addr);
+                        continue;
                     }
-                    _ => {}
+                };
+                observer.observe(addr, &msg)?;
+                if observer.concluded(addresses.len()) {
+                    break;
                 }
             }
             Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
@@ -357,35 +322,7 @@ async fn main() -> Result<()> {
             Err(e) => return Err(Error::from(e)),
         }
     }
-    if appearances.len() != addresses.len() {
-        return Err(anyhow!(
-            "only {}/{} nodes broadcasted appearance messages",
-            appearances.len(),
-            addresses.len()
-        ));
-    }
-    if result_count != addresses.len() {
-        return Err(anyhow!(
-            "only {}/{} nodes concluded election",
-            result_count,
-            addresses.len()
-        ));
-    }
-    if master.is_none() {
-        return Err(anyhow!("failed to get connection details from master"));
-    }
-    let master = master.unwrap();
-    let vote_count = votes.get(&master).unwrap_or(&HashSet::new()).len();
-    if vote_count < addresses.len() {
-        return Err(anyhow!(
-            "master received only {}/{} votes before observing result",
-            vote_count,
-            addresses.len()
-        ));
-    }
-    if election_results.iter().any(|addr| addr != &master) {
-        return Err(anyhow!("nodes disagree on outcome of election"));
-    }
+    let master = observer.check(addresses.len())?;
     let master = match master {
         SocketAddr::V4(addr) => addr.ip().to_string(),
         SocketAddr::V6(addr) => addr.ip().to_string(),
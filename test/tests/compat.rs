@@ -0,0 +1,117 @@
+//! Decodes the frozen datagrams under `corpus/v<N>/`, one directory per
+//! protocol version, to catch changes that would break mixed-version
+//! clusters. After bumping `PROTOCOL_VERSION`, add the new version's
+//! corpus with `cargo test --test compat -- --ignored`.
+
+use homesec_bootstrap::*;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+const KEY: &[u8] = b"homesec compatibility corpus key";
+
+fn corpus_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("corpus")
+}
+
+/// Corpus datagrams are sealed at a fixed time so they never go stale.
+fn codec() -> Codec {
+    let clock = ManualClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+    Codec::new(Authenticator::with_clock(KEY.to_vec(), Arc::new(clock)).unwrap())
+}
+
+fn samples() -> Vec<(&'static str, Message)> {
+    let hid = Uuid::from_u128(0x0123_4567_89ab_cdef_0123_4567_89ab_cdef);
+    let addr = "192.168.0.10:43000".parse().unwrap();
+    vec![
+        (
+            "appearance",
+            Message::Appearance(AppearanceMessage {
+                priority: 1234,
+                is_master: true,
+                hid,
+                static_priority: 5,
+                master_eligible: true,
+                labels: vec![("homesec/storage".to_string(), "ssd".to_string())].into_iter().collect(),
+                term: 3,
+                role: Some(Role::Server),
+            }),
+        ),
+        ("cast-vote", Message::CastVote(CastVote { addr, hid, term: 3 })),
+        ("reset", Message::Reset),
+        ("election-result", Message::ElectionResult(ElectionResult { addr, hid, term: 3 })),
+        (
+            "connection-details",
+            Message::ConnectionDetails(ConnectionDetails {
+                hid,
+                servers: vec![hid, Uuid::from_u128(0xfedc_ba98_7654_3210)],
+                artifacts: Some(ArtifactSource {
+                    port: 43080,
+                    sums: [0xab; 32],
+                }),
+                term: 3,
+                api_addr: Some("2001:db8::10".parse().unwrap()),
+            }),
+        ),
+        ("join-request", Message::JoinRequest(JoinRequest { hid })),
+        (
+            "join-response",
+            Message::JoinResponse(JoinResponse {
+                hid,
+                master: hid,
+                nonce: [7; 12],
+                token: vec![0xde, 0xad, 0xbe, 0xef],
+            }),
+        ),
+        ("leave", Message::Leave(LeaveMessage { hid })),
+    ]
+}
+
+#[test]
+#[ignore]
+fn write_corpus() {
+    let dir = corpus_dir().join(format!("v{}", PROTOCOL_VERSION));
+    std::fs::create_dir_all(&dir).unwrap();
+    let codec = codec();
+    for (name, msg) in samples() {
+        let path = dir.join(format!("{:02}-{}.bin", msg.kind(), name));
+        std::fs::write(path, codec.encode(&msg).unwrap()).unwrap();
+    }
+}
+
+#[test]
+fn decodes_every_version() {
+    let mut versions = std::fs::read_dir(corpus_dir())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect::<Vec<_>>();
+    versions.sort();
+    assert!(
+        versions.iter().any(|v| v.ends_with(format!("v{}", PROTOCOL_VERSION))),
+        "no corpus for protocol version {}",
+        PROTOCOL_VERSION
+    );
+    for version in versions {
+        let current = version.ends_with(format!("v{}", PROTOCOL_VERSION));
+        for entry in std::fs::read_dir(&version).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_stem().unwrap().to_str().unwrap();
+            let kind: u16 = name[..2].parse().unwrap();
+            let data = std::fs::read(&path).unwrap();
+            let msg = match codec().decode(&data) {
+                Ok(Decoded::Message(msg)) => msg,
+                Ok(Decoded::Skipped(header)) => panic!("{}: skipped kind {}", path.display(), header.kind),
+                Err(e) => panic!("{}: {}", path.display(), e),
+            };
+            assert_eq!(msg.kind(), kind, "{}", path.display());
+            if current {
+                let (_, expected) = samples()
+                    .into_iter()
+                    .find(|(sample, _)| name[3..] == **sample)
+                    .unwrap_or_else(|| panic!("{}: no sample named {}", path.display(), &name[3..]));
+                assert_eq!(msg, expected, "{}", path.display());
+            }
+        }
+    }
+}